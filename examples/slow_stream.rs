@@ -1,4 +1,4 @@
-use chrono::NaiveDateTime;
+use chrono::{DateTime, Utc};
 use futures::{Future, Stream};
 use http::Uri;
 use hyper_multipart::{Error, Multipart, MultipartChunks};
@@ -25,7 +25,7 @@ fn main() {
                     Ok(())
                 }
 
-                Err(e) => Err(Error::from(e)),
+                Err(e) => Err(e),
             },
         )
         .map_err(|e| error!("Error: {}", e));
@@ -37,28 +37,16 @@ pub fn handle_stream(s: MultipartChunks<hyper::Body>) {
     let stream = s
         .throttle(Duration::from_millis(1000))
         .inspect(|part| {
-            let headers = part.headers();
-
-            let ts = headers
-                .get("x-timestamp")
-                .expect("Getting x-timestamp")
-                .to_str()
-                .expect("Convering x-timestamp to str")
-                .parse::<f64>()
-                .expect("Parse x-timestamp as f64") as i64;
-
-            let sent_ts: i64 = headers
-                .get("x-sendtimestamp")
-                .expect("Getting x-sendtimestamp")
-                .to_str()
-                .expect("Convering x-sendtimestamp to str")
-                .parse::<f64>()
-                .expect("Parse x-sendtimestamp as f64") as i64;
-
-            let ts_date = NaiveDateTime::from_timestamp(ts, 0);
-            let sent_ts_date = NaiveDateTime::from_timestamp(sent_ts, 0);
-
-            println!("Timestamp: {}.     Sent At: {}", ts_date, sent_ts_date);
+            let meta = part.frame_meta();
+
+            match (meta.timestamp(), meta.send_timestamp()) {
+                (Ok(ts), Ok(sent_ts)) => println!(
+                    "Timestamp: {}.     Sent At: {}",
+                    DateTime::<Utc>::from(ts),
+                    DateTime::<Utc>::from(sent_ts)
+                ),
+                (Err(e), _) | (_, Err(e)) => error!("Reading frame timestamps: {}", e),
+            }
         })
         .for_each(|_| Ok(()))
         .map_err(|e| error!("Print stream: {}", e));
@@ -24,7 +24,7 @@ fn main() {
                     Ok(())
                 }
 
-                Err(e) => Err(Error::from(e)),
+                Err(e) => Err(e),
             },
         )
         .map_err(|e| debug!("Error: {}", e));
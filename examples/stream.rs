@@ -22,7 +22,7 @@ fn main() {
                     Ok(())
                 }
 
-                Err(e) => Err(Error::from(e)),
+                Err(e) => Err(e),
             },
         )
         .map_err(|e| error!("Error: {}", e));
@@ -0,0 +1,119 @@
+//! Multipart boundaries.
+//!
+//! Generated boundaries are 40 characters from the 64 characters
+//! `A-Z a-z 0-9 - _`, a subset of the RFC 2046 `bcharsnospace`. The characters
+//! come from the randomly keyed SipHash of the standard library, hashing a
+//! counter and the current time. SipHash is not a random number generator: all
+//! boundaries of a thread derive from one 128 bit key, so they are hard to guess
+//! for anyone without that key but they are not cryptographically random.
+//!
+//! Boundaries are therefore not secret. A body crafted by someone who can
+//! observe earlier boundaries could contain a later one, which is why in-memory
+//! parts are checked for collisions by the `Encoder`, and `MixedReplace` skips
+//! parts containing its boundary. Bodies that are not crafted, such as camera
+//! frames, collide only by chance, which for 40 characters is negligible.
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Length of generated boundaries.
+pub const GENERATED_LEN: usize = 40;
+
+/// Max length of a boundary according to RFC 2046.
+pub const MAX_LEN: usize = 70;
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+/// Generates a random boundary.
+pub fn generate() -> String {
+    let mut boundary = String::with_capacity(GENERATED_LEN);
+
+    while boundary.len() < GENERATED_LEN {
+        let mut bits = random_u64();
+
+        // 10 characters of 6 bits each per random u64.
+        for _ in 0..10 {
+            if boundary.len() == GENERATED_LEN {
+                break;
+            }
+            boundary.push(ALPHABET[(bits & 0x3f) as usize] as char);
+            bits >>= 6;
+        }
+    }
+
+    boundary
+}
+
+/// Checks the boundary against the RFC 2046 grammar, 1 to 70 `bchars` not
+/// ending with a space.
+pub fn is_valid(boundary: &str) -> bool {
+    let bs = boundary.as_bytes();
+
+    !bs.is_empty()
+        && bs.len() <= MAX_LEN
+        && bs.iter().all(|b| is_bchar(*b))
+        && bs[bs.len() - 1] != b' '
+}
+
+fn is_bchar(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"'()+_,-./:=? ".contains(&b)
+}
+
+/// Returns true if the delimiter for `boundary` occurs in `data`.
+pub(crate) fn collides(boundary: &str, data: &[u8]) -> bool {
+    let delimiter = format!("--{}", boundary);
+    twoway::find_bytes(data, delimiter.as_bytes()).is_some()
+}
+
+/// 64 bits hashed from a counter and the time with a per-thread SipHash key.
+fn random_u64() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.write_u128(nanos);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn generated_boundaries_are_valid_and_unique() {
+        let boundaries: Vec<String> = (0..100).map(|_| generate()).collect();
+
+        for b in &boundaries {
+            assert_eq!(GENERATED_LEN, b.len());
+            assert!(is_valid(b), "{}", b);
+        }
+
+        let mut unique = boundaries.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(boundaries.len(), unique.len());
+    }
+
+    #[test]
+    fn validate_boundaries() {
+        assert!(is_valid("simple boundary"));
+        assert!(is_valid("gc0pJq0M:08jU534c0p"));
+        assert!(is_valid("'()+_,-./:=?"));
+
+        assert!(!is_valid(""));
+        assert!(!is_valid("trailing space "));
+        assert!(!is_valid("semi;colon"));
+        assert!(!is_valid("quote\""));
+        assert!(!is_valid(&"a".repeat(71)));
+    }
+}
@@ -0,0 +1,366 @@
+use crate::{Error, Part};
+use futures::{
+    task::{self, Task},
+    Async, Future, Poll, Stream,
+};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex, MutexGuard, PoisonError, Weak},
+};
+
+/// Queues and the registry are left consistent between statements, a
+/// subscriber panicking while holding a lock does not make them unusable.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// What to do with a subscriber whose queue is full when a new part arrives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lagging {
+    /// Drop the oldest queued part to make room for the new one.
+    DropOldest,
+    /// Drop the new part.
+    DropNewest,
+    /// Disconnect the subscriber, its stream fails with `Error::Lagged`.
+    Disconnect,
+}
+
+/// Drives an upstream part stream and hands every part to all subscribers.
+/// Parts are cloned, which only bumps the reference counts of their `Bytes`.
+/// Any other `Clone` item can be broadcast as well, the parts being cheap to
+/// clone is what matters.
+///
+/// The upstream is consumed as fast as it produces parts, independently of
+/// the subscribers. Every subscriber has its own bounded queue and `Lagging`
+/// policy, so a slow subscriber never stalls the others. Parts arriving while
+/// there are no subscribers are discarded.
+pub struct Broadcast<S: Stream> {
+    inner: S,
+    registry: Arc<Mutex<Registry<S::Item>>>,
+}
+
+/// Handle used to create new subscribers of a `Broadcast`.
+pub struct BroadcastHandle<T = Part> {
+    registry: Arc<Mutex<Registry<T>>>,
+}
+
+/// A stream of the parts of a `Broadcast`, starting from the next part the
+/// upstream produces.
+pub struct Subscriber<T = Part> {
+    queue: Arc<Mutex<Queue<T>>>,
+    registry: Arc<Mutex<Registry<T>>>,
+}
+
+struct Registry<T> {
+    queues: Vec<Weak<Mutex<Queue<T>>>>,
+    end: Option<End>,
+}
+
+#[derive(Clone)]
+enum End {
+    Done,
+    Failed(String),
+}
+
+struct Queue<T> {
+    parts: VecDeque<T>,
+    capacity: usize,
+    lagging: Lagging,
+    dropped: u64,
+    lagged: bool,
+    end: Option<End>,
+    task: Option<Task>,
+}
+
+impl<S> Broadcast<S>
+where
+    S: Stream<Error = Error>,
+    S::Item: Clone,
+{
+    /// Returns the future driving the upstream, which must be spawned, together
+    /// with a handle for subscribing to it.
+    pub fn new(inner: S) -> (Self, BroadcastHandle<S::Item>) {
+        let registry = Arc::new(Mutex::new(Registry {
+            queues: Vec::new(),
+            end: None,
+        }));
+        let handle = BroadcastHandle {
+            registry: registry.clone(),
+        };
+
+        (Self { inner, registry }, handle)
+    }
+
+    fn finish(&self, end: End) {
+        let mut registry = lock(&self.registry);
+
+        for queue in registry.queues.drain(..).filter_map(|q| q.upgrade()) {
+            let mut queue = lock(&queue);
+            queue.end = Some(end.clone());
+            queue.notify();
+        }
+
+        registry.end = Some(end);
+    }
+}
+
+impl<S> Future for Broadcast<S>
+where
+    S: Stream<Error = Error>,
+    S::Item: Clone,
+{
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        loop {
+            match self.inner.poll() {
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+
+                Ok(Async::Ready(Some(part))) => {
+                    let mut registry = lock(&self.registry);
+
+                    registry.queues.retain(|queue| match queue.upgrade() {
+                        Some(queue) => lock(&queue).push(&part),
+                        None => false,
+                    });
+                }
+
+                Ok(Async::Ready(None)) => {
+                    self.finish(End::Done);
+                    return Ok(Async::Ready(()));
+                }
+
+                Err(e) => {
+                    log::debug!("Broadcast upstream failed: {}", e);
+                    self.finish(End::Failed(e.to_string()));
+                    return Ok(Async::Ready(()));
+                }
+            }
+        }
+    }
+}
+
+// Derived `Clone` would require `T: Clone`.
+impl<T> Clone for BroadcastHandle<T> {
+    fn clone(&self) -> Self {
+        Self {
+            registry: self.registry.clone(),
+        }
+    }
+}
+
+impl<T> BroadcastHandle<T> {
+    /// Subscribes with a queue of `capacity` parts, handling overflow according
+    /// to `lagging`.
+    pub fn subscribe(&self, capacity: usize, lagging: Lagging) -> Subscriber<T> {
+        let mut registry = lock(&self.registry);
+
+        let queue = Arc::new(Mutex::new(Queue {
+            parts: VecDeque::with_capacity(capacity),
+            capacity: capacity.max(1),
+            lagging,
+            dropped: 0,
+            lagged: false,
+            end: registry.end.clone(),
+            task: None,
+        }));
+
+        if registry.end.is_none() {
+            registry.queues.push(Arc::downgrade(&queue));
+        }
+
+        Subscriber {
+            queue,
+            registry: self.registry.clone(),
+        }
+    }
+
+    /// Number of currently connected subscribers.
+    pub fn subscribers(&self) -> usize {
+        let registry = lock(&self.registry);
+        registry
+            .queues
+            .iter()
+            .filter(|q| q.strong_count() > 0)
+            .count()
+    }
+}
+
+impl<T> Subscriber<T> {
+    /// Number of parts this subscriber has missed because its queue was full.
+    pub fn dropped(&self) -> u64 {
+        lock(&self.queue).dropped
+    }
+}
+
+/// Cloning a subscriber creates a new subscription with the same capacity and
+/// lagging policy. It starts from the next part, not from the parts queued
+/// in the original.
+impl<T> Clone for Subscriber<T> {
+    fn clone(&self) -> Self {
+        let (capacity, lagging) = {
+            let queue = lock(&self.queue);
+            (queue.capacity, queue.lagging)
+        };
+
+        BroadcastHandle {
+            registry: self.registry.clone(),
+        }
+        .subscribe(capacity, lagging)
+    }
+}
+
+impl<T> Stream for Subscriber<T> {
+    type Item = T;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<T>, Error> {
+        let mut queue = lock(&self.queue);
+
+        if let Some(part) = queue.parts.pop_front() {
+            return Ok(Async::Ready(Some(part)));
+        }
+
+        // Errors are reported once, after that the stream is done.
+        if queue.lagged {
+            queue.lagged = false;
+            queue.end = Some(End::Done);
+            return Err(Error::Lagged);
+        }
+
+        match queue.end.take() {
+            Some(End::Done) => {
+                queue.end = Some(End::Done);
+                Ok(Async::Ready(None))
+            }
+            Some(End::Failed(msg)) => {
+                queue.end = Some(End::Done);
+                Err(Error::InnerStream(msg))
+            }
+            None => {
+                queue.task = Some(task::current());
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+impl<T: Clone> Queue<T> {
+    /// Queues the part, returns false if the subscriber was disconnected.
+    fn push(&mut self, part: &T) -> bool {
+        if self.parts.len() >= self.capacity {
+            self.dropped += 1;
+
+            match self.lagging {
+                Lagging::DropNewest => return true,
+                Lagging::DropOldest => {
+                    self.parts.pop_front();
+                }
+                Lagging::Disconnect => {
+                    self.lagged = true;
+                    self.notify();
+                    return false;
+                }
+            }
+        }
+
+        self.parts.push_back(part.clone());
+        self.notify();
+        true
+    }
+}
+
+impl<T> Queue<T> {
+    fn notify(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.notify();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn parts(n: usize) -> impl Stream<Item = Part, Error = Error> {
+        let parts: Vec<Part> = (0..n)
+            .map(|i| Part::from(format!("X-Index: {}\r\n\r\npart{}", i, i).as_bytes()))
+            .collect();
+        futures::stream::iter_ok(parts)
+    }
+
+    fn bodies(s: Subscriber) -> Vec<Result<String, String>> {
+        s.wait()
+            .map(|r| {
+                r.map(|p| String::from_utf8(p.body().to_vec()).unwrap())
+                    .map_err(|e| e.to_string())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn subscribers_share_parts() {
+        let (broadcast, handle) = Broadcast::new(parts(3));
+        let a = handle.subscribe(10, Lagging::Disconnect);
+        let b = a.clone();
+        assert_eq!(2, handle.subscribers());
+
+        broadcast.wait().unwrap();
+
+        let mut a = a.wait();
+        let mut b = b.wait();
+        for _ in 0..3 {
+            let pa = a.next().unwrap().unwrap();
+            let pb = b.next().unwrap().unwrap();
+
+            // The body is shared, not copied.
+            assert_eq!(pa.body().as_ptr(), pb.body().as_ptr());
+        }
+
+        assert!(a.next().is_none());
+        assert!(b.next().is_none());
+    }
+
+    #[test]
+    fn lagging_subscribers_are_handled_per_subscriber() {
+        let (broadcast, handle) = Broadcast::new(parts(5));
+        let fast = handle.subscribe(5, Lagging::Disconnect);
+        let oldest = handle.subscribe(2, Lagging::DropOldest);
+        let newest = handle.subscribe(2, Lagging::DropNewest);
+        let disconnect = handle.subscribe(2, Lagging::Disconnect);
+
+        broadcast.wait().unwrap();
+
+        assert_eq!(3, oldest.dropped());
+        assert_eq!(3, newest.dropped());
+
+        let ok = |s: &str| Ok(s.to_string());
+        assert_eq!(
+            vec![
+                ok("part0"),
+                ok("part1"),
+                ok("part2"),
+                ok("part3"),
+                ok("part4")
+            ],
+            bodies(fast)
+        );
+        assert_eq!(vec![ok("part3"), ok("part4")], bodies(oldest));
+        assert_eq!(vec![ok("part0"), ok("part1")], bodies(newest));
+
+        let res = bodies(disconnect);
+        assert_eq!(3, res.len());
+        assert!(res[2].is_err());
+    }
+
+    #[test]
+    fn late_subscribers_see_the_end() {
+        let (broadcast, handle) = Broadcast::new(parts(1));
+        broadcast.wait().unwrap();
+
+        let late = handle.subscribe(1, Lagging::DropOldest);
+        assert!(bodies(late).is_empty());
+    }
+}
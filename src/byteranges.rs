@@ -0,0 +1,408 @@
+//! Serving `multipart/byteranges` responses to http Range requests.
+
+use crate::{boundary, encoder::part_head};
+use bytes::Bytes;
+use futures::{Async, Poll, Stream};
+use http::{
+    header::{HeaderValue, ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE},
+    StatusCode,
+};
+use std::{
+    io::{self, SeekFrom},
+    ops::Range,
+};
+use tokio::io::AsyncRead;
+
+/// Size of the chunks read from the source.
+const READ_CHUNK: usize = 64 * 1024;
+
+/// Max number of ranges in a Range header, more are ignored, RFC 7233 section 6.1.
+pub const MAX_RANGES: usize = 32;
+
+/// A byte source that can be read from any position.
+pub trait Seekable: AsyncRead + Send + 'static {
+    fn poll_seek(&mut self, pos: SeekFrom) -> Poll<u64, io::Error>;
+}
+
+impl Seekable for tokio::fs::File {
+    fn poll_seek(&mut self, pos: SeekFrom) -> Poll<u64, io::Error> {
+        tokio::fs::File::poll_seek(self, pos)
+    }
+}
+
+impl<T> Seekable for io::Cursor<T>
+where
+    T: AsRef<[u8]> + Send + 'static,
+{
+    fn poll_seek(&mut self, pos: SeekFrom) -> Poll<u64, io::Error> {
+        io::Seek::seek(self, pos).map(Async::Ready)
+    }
+}
+
+/// Parses the value of a Range header for a resource of `len` bytes.
+///
+/// Returns `None` when the header is not a valid `bytes` range or has more
+/// than `MAX_RANGES` ranges, in which case it should be ignored. Unsatisfiable
+/// ranges are left out, so an empty list means none of the ranges can be
+/// served. The ranges are sorted, with overlapping and adjacent ranges merged,
+/// so a resource is never served more than once in a response.
+pub fn parse_range(value: &str, len: u64) -> Option<Vec<Range<u64>>> {
+    let value = value.trim();
+    if !value.starts_with("bytes=") {
+        return None;
+    }
+
+    let mut ranges: Vec<Range<u64>> = Vec::new();
+
+    let specs = value["bytes=".len()..]
+        .split(',')
+        .map(str::trim)
+        .filter(|spec| !spec.is_empty());
+
+    for (i, spec) in specs.enumerate() {
+        if i == MAX_RANGES {
+            return None;
+        }
+
+        let dash = spec.find('-')?;
+        let (first, last) = (spec[..dash].trim(), spec[dash + 1..].trim());
+
+        let range = if first.is_empty() {
+            // Suffix range, the last n bytes.
+            let n: u64 = last.parse().ok()?;
+            len.saturating_sub(n)..len
+        } else {
+            let start: u64 = first.parse().ok()?;
+            let end = match last {
+                "" => len,
+                last => {
+                    let last: u64 = last.parse().ok()?;
+                    if last < start {
+                        return None;
+                    }
+                    last.saturating_add(1).min(len)
+                }
+            };
+
+            start..end
+        };
+
+        if range.start < range.end {
+            ranges.push(range);
+        }
+    }
+
+    ranges.sort_by_key(|r| r.start);
+    let mut merged: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+
+    Some(merged)
+}
+
+/// Builds the response to a request for `source`, a resource of `len` bytes,
+/// with the given Range header.
+///
+/// - Without a valid Range header, or with more than `MAX_RANGES` ranges, the
+///   whole resource is served with 200 OK.
+/// - A single range is served as 206 Partial Content with a Content-Range header.
+/// - Several ranges are served as 206 with a `multipart/byteranges` body, where
+///   every part has its own Content-Range header.
+/// - When no range is satisfiable, 416 Range Not Satisfiable is returned.
+///
+/// The Content-Length is always set, since the length of every part is known.
+pub fn response<R: Seekable>(
+    range: Option<&HeaderValue>,
+    source: R,
+    len: u64,
+    content_type: &mime::Mime,
+) -> hyper::Response<hyper::Body> {
+    let ranges = range
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| parse_range(v, len));
+
+    let builder = || {
+        let mut builder = hyper::Response::builder();
+        builder.header(ACCEPT_RANGES, "bytes");
+        builder
+    };
+
+    let built = match ranges {
+        None => builder()
+            .header(CONTENT_TYPE, content_type.as_ref())
+            .header(CONTENT_LENGTH, len)
+            .body(reader(source, vec![Segment::Data(0..len)])),
+
+        Some(ref ranges) if ranges.is_empty() => builder()
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(CONTENT_RANGE, format!("bytes */{}", len).as_str())
+            .body(hyper::Body::empty()),
+
+        Some(mut ranges) if ranges.len() == 1 => {
+            let range = ranges.remove(0);
+
+            builder()
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(CONTENT_TYPE, content_type.as_ref())
+                .header(CONTENT_RANGE, content_range(&range, len).as_str())
+                .header(CONTENT_LENGTH, range.end - range.start)
+                .body(reader(source, vec![Segment::Data(range)]))
+        }
+
+        Some(ranges) => {
+            let boundary = boundary::generate();
+            let mut segments = Vec::with_capacity(ranges.len() * 3 + 1);
+
+            for range in ranges {
+                let headers = format!(
+                    "Content-Type: {}\r\nContent-Range: {}",
+                    content_type,
+                    content_range(&range, len)
+                );
+
+                segments.push(Segment::Literal(part_head(&boundary, headers.as_bytes())));
+                segments.push(Segment::Data(range));
+                segments.push(Segment::Literal(Bytes::from_static(b"\r\n")));
+            }
+            segments.push(Segment::Literal(Bytes::from(format!(
+                "--{}--\r\n",
+                boundary
+            ))));
+
+            let body_len: u64 = segments.iter().map(Segment::len).sum();
+            let multipart_type = format!("multipart/byteranges; boundary={}", boundary);
+
+            builder()
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(CONTENT_TYPE, multipart_type.as_str())
+                .header(CONTENT_LENGTH, body_len)
+                .body(reader(source, segments))
+        }
+    };
+
+    built.expect("Building byteranges response")
+}
+
+fn content_range(range: &Range<u64>, len: u64) -> String {
+    format!("bytes {}-{}/{}", range.start, range.end - 1, len)
+}
+
+fn reader<R: Seekable>(source: R, segments: Vec<Segment>) -> hyper::Body {
+    hyper::Body::wrap_stream(RangeReader {
+        source,
+        segments: segments.into_iter(),
+        current: None,
+    })
+}
+
+enum Segment {
+    Literal(Bytes),
+    Data(Range<u64>),
+}
+
+impl Segment {
+    fn len(&self) -> u64 {
+        match *self {
+            Segment::Literal(ref bs) => bs.len() as u64,
+            Segment::Data(ref r) => r.end - r.start,
+        }
+    }
+}
+
+enum Reading {
+    Seeking(Range<u64>),
+    Reading { remaining: u64 },
+}
+
+/// Streams the segments, reading the data segments from the source.
+struct RangeReader<R> {
+    source: R,
+    segments: std::vec::IntoIter<Segment>,
+    current: Option<Reading>,
+}
+
+impl<R: Seekable> Stream for RangeReader<R> {
+    type Item = Bytes;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, io::Error> {
+        loop {
+            match self.current {
+                None => match self.segments.next() {
+                    None => return Ok(Async::Ready(None)),
+                    Some(Segment::Literal(bs)) => return Ok(Async::Ready(Some(bs))),
+                    Some(Segment::Data(range)) => self.current = Some(Reading::Seeking(range)),
+                },
+
+                Some(Reading::Seeking(ref range)) => {
+                    let pos =
+                        futures::try_ready!(self.source.poll_seek(SeekFrom::Start(range.start)));
+                    if pos != range.start {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "Seek past end of source",
+                        ));
+                    }
+
+                    self.current = Some(Reading::Reading {
+                        remaining: range.end - range.start,
+                    });
+                }
+
+                Some(Reading::Reading { remaining: 0 }) => self.current = None,
+
+                Some(Reading::Reading { ref mut remaining }) => {
+                    let mut buf = vec![0u8; READ_CHUNK.min(*remaining as usize)];
+                    let n = futures::try_ready!(self.source.poll_read(&mut buf));
+
+                    if n == 0 {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "Source ended before the range",
+                        ));
+                    }
+
+                    *remaining -= n as u64;
+                    buf.truncate(n);
+                    return Ok(Async::Ready(Some(Bytes::from(buf))));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::Multipart;
+    use futures::Future;
+
+    fn source() -> io::Cursor<Vec<u8>> {
+        io::Cursor::new((0..100).collect())
+    }
+
+    fn range(value: &str) -> Option<HeaderValue> {
+        Some(HeaderValue::from_str(value).unwrap())
+    }
+
+    fn bounds(value: &str, len: u64) -> Option<Vec<(u64, u64)>> {
+        parse_range(value, len).map(|ranges| ranges.iter().map(|r| (r.start, r.end)).collect())
+    }
+
+    #[test]
+    fn parse_ranges() {
+        assert_eq!(Some(vec![(0, 500)]), bounds("bytes=0-499", 10000));
+        assert_eq!(Some(vec![(9500, 10000)]), bounds("bytes=-500", 10000));
+        assert_eq!(Some(vec![(9500, 10000)]), bounds("bytes=9500-", 10000));
+        assert_eq!(
+            Some(vec![(0, 1), (9999, 10000)]),
+            bounds("bytes=0-0, -1", 10000)
+        );
+        assert_eq!(Some(vec![(90, 100)]), bounds("bytes=90-200", 100));
+        assert_eq!(Some(vec![]), bounds("bytes=100-200", 100));
+
+        // Overlapping and adjacent ranges are merged.
+        assert_eq!(
+            Some(vec![(0, 5), (10, 30), (50, 60)]),
+            bounds("bytes=50-59, 10-19, 20-29, 15-24, 0-4", 100)
+        );
+
+        assert_eq!(None, bounds("items=0-1", 100));
+        assert_eq!(None, bounds("bytes=5-1", 100));
+        assert_eq!(None, bounds("bytes=a-b", 100));
+    }
+
+    #[test]
+    fn multiple_ranges() {
+        let response = response(
+            range("bytes=0-9, 50-54, -5").as_ref(),
+            source(),
+            100,
+            &mime::APPLICATION_OCTET_STREAM,
+        );
+
+        assert_eq!(StatusCode::PARTIAL_CONTENT, response.status());
+        let content_length: u64 = response.headers()[CONTENT_LENGTH]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+
+        let (parts, body) = response.into_parts();
+        let raw = body.concat2().wait().unwrap().into_bytes();
+        assert_eq!(content_length, raw.len() as u64);
+
+        let parts: Vec<_> = (parts.headers, raw)
+            .into_multipart()
+            .unwrap()
+            .wait()
+            .map(|p| p.unwrap())
+            .collect();
+
+        assert_eq!(3, parts.len());
+        assert_eq!("bytes 0-9/100", parts[0].headers()[CONTENT_RANGE]);
+        assert_eq!(&(0..10).collect::<Vec<u8>>()[..], parts[0].body());
+        assert_eq!("bytes 50-54/100", parts[1].headers()[CONTENT_RANGE]);
+        assert_eq!(&[50, 51, 52, 53, 54], parts[1].body());
+        assert_eq!("bytes 95-99/100", parts[2].headers()[CONTENT_RANGE]);
+        assert_eq!("application/octet-stream", parts[2].headers()[CONTENT_TYPE]);
+    }
+
+    #[test]
+    fn limit_ranges() {
+        // Overlapping ranges cannot amplify the response.
+        let repeated = format!("bytes={}", vec!["0-"; MAX_RANGES].join(","));
+        assert_eq!(Some(vec![(0, 100)]), bounds(&repeated, 100));
+
+        let merged = response(range(&repeated).as_ref(), source(), 100, &mime::TEXT_PLAIN);
+        assert_eq!(StatusCode::PARTIAL_CONTENT, merged.status());
+        assert_eq!("bytes 0-99/100", merged.headers()[CONTENT_RANGE]);
+
+        // Too many ranges are ignored and the whole resource is served.
+        let many: Vec<String> = (0..=MAX_RANGES)
+            .map(|i| format!("{}-{}", 2 * i, 2 * i))
+            .collect();
+        let many = format!("bytes={}", many.join(","));
+        assert_eq!(None, bounds(&many, 100));
+
+        let ignored = response(range(&many).as_ref(), source(), 100, &mime::TEXT_PLAIN);
+        assert_eq!(StatusCode::OK, ignored.status());
+    }
+
+    #[test]
+    fn single_range() {
+        let response = response(
+            range("bytes=10-14").as_ref(),
+            source(),
+            100,
+            &mime::TEXT_PLAIN,
+        );
+
+        assert_eq!(StatusCode::PARTIAL_CONTENT, response.status());
+        assert_eq!("bytes 10-14/100", response.headers()[CONTENT_RANGE]);
+
+        let body = response.into_body().concat2().wait().unwrap();
+        assert_eq!(&[10, 11, 12, 13, 14], &body[..]);
+    }
+
+    #[test]
+    fn unsatisfiable_and_missing_ranges() {
+        let unsatisfiable = response(
+            range("bytes=200-300").as_ref(),
+            source(),
+            100,
+            &mime::TEXT_PLAIN,
+        );
+        assert_eq!(StatusCode::RANGE_NOT_SATISFIABLE, unsatisfiable.status());
+        assert_eq!("bytes */100", unsatisfiable.headers()[CONTENT_RANGE]);
+
+        let full = response(None, source(), 100, &mime::TEXT_PLAIN);
+        assert_eq!(StatusCode::OK, full.status());
+        assert_eq!(100, full.into_body().concat2().wait().unwrap().len());
+    }
+}
@@ -0,0 +1,184 @@
+//! Decoding of part bodies by the `charset` parameter of their Content-Type.
+
+use crate::Error;
+
+mod tables;
+
+/// A character set a text part can be decoded from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Charset {
+    /// The default, what browsers send form fields in and a superset of the
+    /// us-ascii default of RFC 2046.
+    #[default]
+    Utf8,
+    UsAscii,
+    /// ISO-8859-1 to ISO-8859-16, there is no part 12.
+    Iso8859(u8),
+    /// Windows-1250 to Windows-1258.
+    Windows(u16),
+}
+
+impl Charset {
+    /// Looks up a charset by its case-insensitive IANA name or common alias.
+    pub fn from_label(label: &str) -> Result<Self, Error> {
+        let name = label.trim().to_ascii_lowercase().replace('_', "-");
+        let unknown = || Error::UnknownCharset(label.to_string());
+
+        let charset = match name.as_str() {
+            "utf-8" | "utf8" => Charset::Utf8,
+            "us-ascii" | "ascii" => Charset::UsAscii,
+            "latin1" | "l1" => Charset::Iso8859(1),
+            _ => {
+                if let Some(part) = name
+                    .strip_prefix("iso-8859-")
+                    .or_else(|| name.strip_prefix("iso8859-"))
+                {
+                    match part.parse() {
+                        Ok(n) if (1..=16).contains(&n) && n != 12 => Charset::Iso8859(n),
+                        _ => return Err(unknown()),
+                    }
+                } else if let Some(cp) = name
+                    .strip_prefix("windows-")
+                    .or_else(|| name.strip_prefix("cp"))
+                {
+                    match cp.parse() {
+                        Ok(n) if (1250..=1258).contains(&n) => Charset::Windows(n),
+                        _ => return Err(unknown()),
+                    }
+                } else {
+                    return Err(unknown());
+                }
+            }
+        };
+
+        Ok(charset)
+    }
+
+    /// Decodes `bytes`, failing on bytes that are invalid in the charset.
+    pub fn decode(self, bytes: &[u8]) -> Result<String, Error> {
+        let invalid = |i: usize| {
+            Error::Decode(format!(
+                "invalid {:?} byte {:#04x} at {}",
+                self, bytes[i], i
+            ))
+        };
+
+        match self {
+            Charset::Utf8 => {
+                String::from_utf8(bytes.to_vec()).map_err(|e| invalid(e.utf8_error().valid_up_to()))
+            }
+            _ => bytes
+                .iter()
+                .enumerate()
+                .map(|(i, b)| self.char(*b).ok_or_else(|| invalid(i)))
+                .collect(),
+        }
+    }
+
+    /// Decodes `bytes`, replacing invalid bytes with U+FFFD.
+    pub fn decode_lossy(self, bytes: &[u8]) -> String {
+        match self {
+            Charset::Utf8 => String::from_utf8_lossy(bytes).into_owned(),
+            _ => bytes
+                .iter()
+                .map(|b| self.char(*b).unwrap_or(char::REPLACEMENT_CHARACTER))
+                .collect(),
+        }
+    }
+
+    /// The character of a single byte, for the single byte charsets.
+    fn char(self, b: u8) -> Option<char> {
+        if b < 0x80 {
+            return Some(b as char);
+        }
+
+        let table = match self {
+            Charset::Utf8 | Charset::UsAscii => return None,
+            Charset::Iso8859(1) => return Some(b as char),
+            Charset::Iso8859(n) => match n {
+                2 => &tables::ISO_8859_2,
+                3 => &tables::ISO_8859_3,
+                4 => &tables::ISO_8859_4,
+                5 => &tables::ISO_8859_5,
+                6 => &tables::ISO_8859_6,
+                7 => &tables::ISO_8859_7,
+                8 => &tables::ISO_8859_8,
+                9 => &tables::ISO_8859_9,
+                10 => &tables::ISO_8859_10,
+                11 => &tables::ISO_8859_11,
+                13 => &tables::ISO_8859_13,
+                14 => &tables::ISO_8859_14,
+                15 => &tables::ISO_8859_15,
+                16 => &tables::ISO_8859_16,
+                _ => return None,
+            },
+            Charset::Windows(n) => match n {
+                1250 => &tables::WINDOWS_1250,
+                1251 => &tables::WINDOWS_1251,
+                1252 => &tables::WINDOWS_1252,
+                1253 => &tables::WINDOWS_1253,
+                1254 => &tables::WINDOWS_1254,
+                1255 => &tables::WINDOWS_1255,
+                1256 => &tables::WINDOWS_1256,
+                1257 => &tables::WINDOWS_1257,
+                1258 => &tables::WINDOWS_1258,
+                _ => return None,
+            },
+        };
+
+        match table[usize::from(b - 0x80)] {
+            0 => None,
+            c => std::char::from_u32(u32::from(c)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn labels() {
+        assert_eq!(Charset::Utf8, Charset::from_label("UTF-8").unwrap());
+        assert_eq!(Charset::Iso8859(1), Charset::from_label("latin1").unwrap());
+        assert_eq!(
+            Charset::Iso8859(15),
+            Charset::from_label("ISO_8859-15").unwrap()
+        );
+        assert_eq!(
+            Charset::Windows(1252),
+            Charset::from_label("cp1252").unwrap()
+        );
+
+        assert!(Charset::from_label("iso-8859-12").is_err());
+        assert!(Charset::from_label("windows-1259").is_err());
+        assert!(Charset::from_label("koi8-r").is_err());
+    }
+
+    #[test]
+    fn single_byte_charsets() {
+        let decode = |label, bytes: &[u8]| Charset::from_label(label).unwrap().decode(bytes);
+
+        assert_eq!("r\u{e4}ka", decode("iso-8859-1", b"r\xe4ka").unwrap());
+        assert_eq!("\u{20ac}5", decode("iso-8859-15", b"\xa45").unwrap());
+        assert_eq!(
+            "\u{20ac}\u{201c}",
+            decode("windows-1252", b"\x80\x93").unwrap()
+        );
+        assert_eq!("\u{416}", decode("windows-1251", b"\xc6").unwrap());
+        assert_eq!("\u{141}", decode("iso-8859-2", b"\xa3").unwrap());
+    }
+
+    #[test]
+    fn strict_and_lossy() {
+        // 0x81 is undefined in windows-1252.
+        let cp1252 = Charset::Windows(1252);
+        assert!(cp1252.decode(b"a\x81").is_err());
+        assert_eq!("a\u{fffd}", cp1252.decode_lossy(b"a\x81"));
+
+        assert!(Charset::UsAscii.decode(b"\xe4").is_err());
+        assert!(Charset::Utf8.decode(b"\xe4").is_err());
+        assert_eq!("\u{fffd}", Charset::Utf8.decode_lossy(b"\xe4"));
+    }
+}
@@ -0,0 +1,324 @@
+//! Parsing of the `Content-Disposition` header, RFC 6266 and RFC 7578.
+//!
+//! Extended parameters (`filename*=UTF-8''%e2%82%ac.txt`, RFC 5987) and RFC 2231
+//! continuations (`filename*0=...; filename*1=...`) are decoded, the resulting
+//! parameter is stored under the name with a trailing `*`.
+
+use crate::{Charset, Error};
+
+/// A parsed `Content-Disposition` header value.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ContentDisposition {
+    disposition: String,
+    params: Vec<(String, String)>,
+}
+
+impl ContentDisposition {
+    /// Parses a header value such as `form-data; name="file"; filename="a.txt"`.
+    ///
+    /// Raw bytes that are not utf-8 are read as latin-1. Quoted values use
+    /// backslash escapes only for `"` and `\`, so windows paths sent unescaped
+    /// by older browsers survive. `%22`, `%0D` and `%0A` in plain values are
+    /// decoded, as that is how browsers escape form-data names and filenames.
+    pub fn parse(value: &[u8]) -> Result<Self, Error> {
+        let value = match std::str::from_utf8(value) {
+            Ok(s) => s.to_string(),
+            Err(_) => value.iter().map(|b| *b as char).collect(),
+        };
+        let invalid = || Error::InvalidHeader(format!("Content-Disposition: {}", value));
+
+        let mut input = value.as_str();
+        let disposition = token(&mut input).to_ascii_lowercase();
+        if disposition.is_empty() {
+            return Err(invalid());
+        }
+
+        let mut params = Vec::new();
+        let mut sections = Vec::new();
+
+        loop {
+            input = input.trim_start();
+            if input.is_empty() {
+                break;
+            }
+
+            input = input.strip_prefix(';').ok_or_else(invalid)?.trim_start();
+            if input.is_empty() {
+                // Tolerate a trailing ';'.
+                break;
+            }
+
+            let name = token(&mut input).to_ascii_lowercase();
+            input = match input.trim_start().strip_prefix('=') {
+                Some(rest) if !name.is_empty() => rest.trim_start(),
+                _ => return Err(invalid()),
+            };
+
+            let value = if input.starts_with('"') {
+                quoted(&mut input).ok_or_else(invalid)?
+            } else {
+                let end = input.find(';').unwrap_or(input.len());
+                let (value, rest) = input.split_at(end);
+                input = rest;
+                value.trim_end().to_string()
+            };
+
+            match name.find('*') {
+                None => params.push((name, unescape(&value))),
+                Some(i) if i + 1 == name.len() => match decode_extended(&value) {
+                    Some(decoded) => params.push((name, decoded)),
+                    None => log::warn!("Skipping undecodable parameter {}={}", name, value),
+                },
+                Some(i) => {
+                    let rest = &name[i + 1..];
+                    let (index, encoded) = match rest.strip_suffix('*') {
+                        Some(index) => (index, true),
+                        None => (rest, false),
+                    };
+                    let index = index.parse::<u32>().map_err(|_| invalid())?;
+                    sections.push((name[..i].to_string(), index, encoded, value));
+                }
+            }
+        }
+
+        sections.sort_by(|a, b| (&a.0, a.1).cmp(&(&b.0, b.1)));
+        let mut rest = &sections[..];
+        while let Some(first) = rest.first() {
+            let len = rest.iter().take_while(|s| s.0 == first.0).count();
+            let (group, next) = rest.split_at(len);
+            rest = next;
+
+            let name = &first.0;
+            match join_sections(group) {
+                Some((true, joined)) => params.push((format!("{}*", name), joined)),
+                Some((false, joined)) => params.push((name.clone(), joined)),
+                None => log::warn!("Skipping undecodable parameter {}*", name),
+            }
+        }
+
+        Ok(ContentDisposition {
+            disposition,
+            params,
+        })
+    }
+
+    /// The disposition type in lower case, e.g. `form-data`, `attachment` or `inline`.
+    pub fn disposition_type(&self) -> &str {
+        &self.disposition
+    }
+
+    /// Looks up a parameter by its case-insensitive name. Decoded extended
+    /// parameters are found under their name with the trailing `*`, e.g. `filename*`.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The `name` parameter, naming the form field.
+    pub fn name(&self) -> Option<&str> {
+        self.param("name*").or_else(|| self.param("name"))
+    }
+
+    /// The file name, preferring `filename*` over `filename` as RFC 6266 recommends.
+    pub fn filename(&self) -> Option<&str> {
+        self.filename_ext().or_else(|| self.param("filename"))
+    }
+
+    /// The decoded `filename*` parameter only.
+    pub fn filename_ext(&self) -> Option<&str> {
+        self.param("filename*")
+    }
+}
+
+/// Takes a token, stopping at whitespace, separators and quotes.
+fn token<'a>(input: &mut &'a str) -> &'a str {
+    let end = input
+        .find(|c: char| c.is_whitespace() || c == ';' || c == '=' || c == '"')
+        .unwrap_or(input.len());
+    let (token, rest) = input.split_at(end);
+    *input = rest;
+    token
+}
+
+/// Takes a quoted string, `None` if it is not terminated.
+fn quoted(input: &mut &str) -> Option<String> {
+    let s = *input;
+    let mut res = String::new();
+    let mut chars = s.char_indices().skip(1);
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => {
+                *input = &s[i + 1..];
+                return Some(res);
+            }
+            '\\' if matches!(s[i + 1..].chars().next(), Some('"') | Some('\\')) => {
+                res.extend(chars.next().map(|(_, c)| c));
+            }
+            c => res.push(c),
+        }
+    }
+
+    None
+}
+
+/// Undoes the percent escapes browsers use in form-data parameters.
+fn unescape(value: &str) -> String {
+    value
+        .replace("%22", "\"")
+        .replace("%0D", "\r")
+        .replace("%0d", "\r")
+        .replace("%0A", "\n")
+        .replace("%0a", "\n")
+}
+
+/// Decodes an RFC 5987 value, `charset'language'percent-encoded`.
+fn decode_extended(value: &str) -> Option<String> {
+    let mut it = value.splitn(3, '\'');
+    let (charset, _language, encoded) = (it.next()?, it.next()?, it.next()?);
+    decode_charset(charset, &percent_decode(encoded))
+}
+
+/// Joins RFC 2231 continuations of one parameter, sorted by index. Returns
+/// whether any section was encoded along with the joined value.
+fn join_sections(sections: &[(String, u32, bool, String)]) -> Option<(bool, String)> {
+    let mut charset = "us-ascii";
+    let mut bytes = Vec::new();
+    let mut encoded = false;
+
+    for (expected, (_, index, is_encoded, value)) in sections.iter().enumerate() {
+        // Sections after a gap are ignored.
+        if *index as usize != expected {
+            break;
+        }
+
+        if !*is_encoded {
+            bytes.extend_from_slice(value.as_bytes());
+            continue;
+        }
+
+        encoded = true;
+        let value = if *index == 0 {
+            // Only the first section carries the charset and language.
+            let mut it = value.splitn(3, '\'');
+            charset = it.next()?;
+            it.next()?;
+            it.next()?
+        } else {
+            value
+        };
+        bytes.extend(percent_decode(value));
+    }
+
+    if encoded {
+        decode_charset(charset, &bytes).map(|s| (true, s))
+    } else {
+        String::from_utf8(bytes).ok().map(|s| (false, s))
+    }
+}
+
+/// Decodes `%XX` escapes, leaving malformed ones as they are.
+fn percent_decode(s: &str) -> Vec<u8> {
+    let bs = s.as_bytes();
+    let mut res = Vec::with_capacity(bs.len());
+    let mut i = 0;
+
+    while i < bs.len() {
+        let hex = bs
+            .get(i + 1..i + 3)
+            .filter(|h| h.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+
+        match (bs[i], hex) {
+            (b'%', Some(b)) => {
+                res.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                res.push(b);
+                i += 1;
+            }
+        }
+    }
+
+    res
+}
+
+fn decode_charset(charset: &str, bytes: &[u8]) -> Option<String> {
+    Charset::from_label(charset).ok()?.decode(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn plain_form_data() {
+        let cd =
+            ContentDisposition::parse(br#"form-data; name="file"; filename="C:\dir\a \"b\".txt""#)
+                .unwrap();
+
+        assert_eq!("form-data", cd.disposition_type());
+        assert_eq!(Some("file"), cd.name());
+        assert_eq!(Some(r#"C:\dir\a "b".txt"#), cd.filename());
+        assert_eq!(None, cd.filename_ext());
+    }
+
+    #[test]
+    fn browser_escapes_and_raw_utf8() {
+        let cd = ContentDisposition::parse(
+            "form-data; name=\"a%22b\"; filename=\"r\u{e4}ksm\u{f6}rg\u{e5}s.txt\"".as_bytes(),
+        )
+        .unwrap();
+
+        assert_eq!(Some("a\"b"), cd.name());
+        assert_eq!(Some("r\u{e4}ksm\u{f6}rg\u{e5}s.txt"), cd.filename());
+    }
+
+    #[test]
+    fn extended_filename() {
+        let cd = ContentDisposition::parse(
+            b"attachment; filename=\"EURO rates.txt\"; FILENAME*=UTF-8''%e2%82%ac%20rates.txt",
+        )
+        .unwrap();
+
+        assert_eq!("attachment", cd.disposition_type());
+        assert_eq!(Some("\u{20ac} rates.txt"), cd.filename());
+        assert_eq!(Some("EURO rates.txt"), cd.param("filename"));
+
+        let cd = ContentDisposition::parse(b"inline; filename*=iso-8859-1'en'%E4.txt").unwrap();
+        assert_eq!(Some("\u{e4}.txt"), cd.filename_ext());
+
+        // Only hex digits form an escape, `%+1` is not a byte.
+        let cd = ContentDisposition::parse(b"inline; filename*=UTF-8''100%+1%zz.txt").unwrap();
+        assert_eq!(Some("100%+1%zz.txt"), cd.filename_ext());
+    }
+
+    #[test]
+    fn continuations() {
+        let cd = ContentDisposition::parse(
+            b"attachment; filename*1*=%20rates; filename*0*=UTF-8''%e2%82%ac; filename*2=.txt",
+        )
+        .unwrap();
+        assert_eq!(Some("\u{20ac} rates.txt"), cd.filename_ext());
+
+        let cd = ContentDisposition::parse(b"attachment; name*0=\"long \"; name*1=name").unwrap();
+        assert_eq!(Some("long name"), cd.name());
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(ContentDisposition::parse(b"").is_err());
+        assert!(ContentDisposition::parse(b"form-data; name").is_err());
+        assert!(ContentDisposition::parse(b"form-data; name=\"open").is_err());
+
+        // Unknown charsets leave only the plain parameter.
+        let cd = ContentDisposition::parse(b"attachment; filename=a.txt; filename*=koi8-r''%C1")
+            .unwrap();
+        assert_eq!(Some("a.txt"), cd.filename());
+    }
+}
@@ -0,0 +1,639 @@
+use crate::{boundary, Error};
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{stream, try_ready, Async, Poll, Stream};
+use http::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_DISPOSITION, CONTENT_TYPE};
+
+type BodyStream = Box<dyn Stream<Item = Bytes, Error = Error> + Send>;
+
+enum PartBody {
+    Bytes(Bytes),
+    /// A streamed body, with its length when known up front.
+    Stream(BodyStream, Option<u64>),
+}
+
+struct EncoderPart {
+    headers: HeaderMap<HeaderValue>,
+    body: PartBody,
+}
+
+/// Builds a multipart body from a list of parts.
+///
+/// Unless a boundary is set with `with_boundary`, a random one is generated.
+/// Whenever an in-memory part is added, its headers and body are checked for
+/// the boundary and a new boundary is generated on a collision. Streamed bodies
+/// cannot be checked ahead of time, see the `boundary` module for the
+/// probability of a collision.
+///
+/// When the lengths of all parts are known, `content_length` returns the exact
+/// length of the encoded body, for services that do not accept chunked uploads.
+///
+/// ```
+/// use hyper_multipart::Encoder;
+///
+/// let encoder = Encoder::form_data()
+///     .text("name", "camera 1")
+///     .file("snapshot", "snap.jpg", mime::IMAGE_JPEG, vec![0xff, 0xd8]);
+///
+/// let content_type = encoder.content_type();
+/// let body: hyper::Body = encoder.into_body();
+/// ```
+pub struct Encoder {
+    /// `multipart/{subtype}` with any parameters but the boundary.
+    media_type: String,
+    boundary: String,
+    fixed_boundary: bool,
+    parts: Vec<EncoderPart>,
+}
+
+impl Encoder {
+    /// An encoder for `multipart/{subtype}`. The subtype may be followed by
+    /// parameters, as in `related; type="application/json"`, a boundary
+    /// parameter is used as if given to `with_boundary`. Fails with
+    /// `Error::InvalidMimeType` unless `multipart/{subtype}` is a valid mime
+    /// type.
+    pub fn new<S: AsRef<str>>(subtype: S) -> Result<Self, Error> {
+        let mime: mime::Mime = format!("multipart/{}", subtype.as_ref())
+            .parse()
+            .map_err(Error::InvalidMimeType)?;
+
+        let mut media_type = format!("multipart/{}", mime.subtype());
+        for (name, value) in mime.params().filter(|(name, _)| *name != mime::BOUNDARY) {
+            media_type.push_str(&format!("; {}=\"{}\"", name, escape_param(value.as_str())));
+        }
+
+        let encoder = Self::with_media_type(media_type);
+        match mime.get_param(mime::BOUNDARY) {
+            Some(boundary) => encoder.with_boundary(boundary.as_str()),
+            None => Ok(encoder),
+        }
+    }
+
+    pub fn form_data() -> Self {
+        Self::with_media_type("multipart/form-data".to_string())
+    }
+
+    pub fn mixed() -> Self {
+        Self::with_media_type("multipart/mixed".to_string())
+    }
+
+    fn with_media_type(media_type: String) -> Self {
+        Self {
+            media_type,
+            boundary: boundary::generate(),
+            fixed_boundary: false,
+            parts: Vec::new(),
+        }
+    }
+
+    /// Uses the given boundary, which is never replaced. It is up to the caller
+    /// to make sure it does not occur in the parts. Fails with
+    /// `Error::InvalidBoundary` unless it is valid according to RFC 2046.
+    pub fn with_boundary<S: Into<String>>(mut self, boundary: S) -> Result<Self, Error> {
+        let boundary = boundary.into();
+        if !boundary::is_valid(&boundary) {
+            return Err(Error::InvalidBoundary(boundary));
+        }
+
+        self.boundary = boundary;
+        self.fixed_boundary = true;
+        Ok(self)
+    }
+
+    pub fn boundary(&self) -> &str {
+        &self.boundary
+    }
+
+    /// Adds a part with an in-memory body.
+    pub fn part<B: Into<Bytes>>(mut self, headers: HeaderMap<HeaderValue>, body: B) -> Self {
+        let part = EncoderPart {
+            headers,
+            body: PartBody::Bytes(body.into()),
+        };
+
+        if !self.fixed_boundary && part.collides(&self.boundary) {
+            self.boundary = loop {
+                let candidate = boundary::generate();
+                let colliding = self
+                    .parts
+                    .iter()
+                    .chain(Some(&part))
+                    .any(|p| p.collides(&candidate));
+
+                if !colliding {
+                    break candidate;
+                }
+            };
+        }
+
+        self.parts.push(part);
+        self
+    }
+
+    /// Adds a part whose body is read from a stream while the body is sent.
+    pub fn stream_part<S, I, E>(self, headers: HeaderMap<HeaderValue>, body: S) -> Self
+    where
+        S: Stream<Item = I, Error = E> + Send + 'static,
+        I: Into<Bytes> + 'static,
+        E: std::fmt::Display + Send + 'static,
+    {
+        self.push_stream(headers, body, None)
+    }
+
+    /// Adds a streamed part whose body is known to be `len` bytes. If the stream
+    /// yields a different number of bytes the encoded body fails with
+    /// `Error::LengthMismatch`.
+    pub fn stream_part_with_len<S, I, E>(
+        self,
+        headers: HeaderMap<HeaderValue>,
+        len: u64,
+        body: S,
+    ) -> Self
+    where
+        S: Stream<Item = I, Error = E> + Send + 'static,
+        I: Into<Bytes> + 'static,
+        E: std::fmt::Display + Send + 'static,
+    {
+        self.push_stream(headers, body, Some(len))
+    }
+
+    fn push_stream<S, I, E>(
+        mut self,
+        headers: HeaderMap<HeaderValue>,
+        body: S,
+        len: Option<u64>,
+    ) -> Self
+    where
+        S: Stream<Item = I, Error = E> + Send + 'static,
+        I: Into<Bytes> + 'static,
+        E: std::fmt::Display + Send + 'static,
+    {
+        let body = body.map(Into::into).map_err(Error::inner);
+
+        self.parts.push(EncoderPart {
+            headers,
+            body: PartBody::Stream(Box::new(body), len),
+        });
+        self
+    }
+
+    /// Adds a `form-data` text field.
+    pub fn text<V: Into<Bytes>>(self, name: &str, value: V) -> Self {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_DISPOSITION, disposition(name, None));
+        self.part(headers, value)
+    }
+
+    /// Adds a `form-data` file field.
+    pub fn file<B: Into<Bytes>>(
+        self,
+        name: &str,
+        filename: &str,
+        content_type: mime::Mime,
+        body: B,
+    ) -> Self {
+        self.part(file_headers(name, filename, &content_type), body)
+    }
+
+    /// Adds a `form-data` file field with a streamed body.
+    pub fn file_stream<S, I, E>(
+        self,
+        name: &str,
+        filename: &str,
+        content_type: mime::Mime,
+        body: S,
+    ) -> Self
+    where
+        S: Stream<Item = I, Error = E> + Send + 'static,
+        I: Into<Bytes> + 'static,
+        E: std::fmt::Display + Send + 'static,
+    {
+        self.stream_part(file_headers(name, filename, &content_type), body)
+    }
+
+    /// Adds a `form-data` file field with a streamed body of `len` bytes, such
+    /// as a file of known size.
+    pub fn file_stream_with_len<S, I, E>(
+        self,
+        name: &str,
+        filename: &str,
+        content_type: mime::Mime,
+        len: u64,
+        body: S,
+    ) -> Self
+    where
+        S: Stream<Item = I, Error = E> + Send + 'static,
+        I: Into<Bytes> + 'static,
+        E: std::fmt::Display + Send + 'static,
+    {
+        self.stream_part_with_len(file_headers(name, filename, &content_type), len, body)
+    }
+
+    /// The exact length of the encoded body, if the lengths of all parts are known.
+    pub fn content_length(&self) -> Option<u64> {
+        let mut len = closing_delimiter(&self.boundary).len() as u64;
+
+        for part in &self.parts {
+            let body_len = match part.body {
+                PartBody::Bytes(ref bs) => bs.len() as u64,
+                PartBody::Stream(_, len) => len?,
+            };
+
+            len += part_head(&self.boundary, &encode_headers(&part.headers)).len() as u64;
+            len += body_len + 2;
+        }
+
+        Some(len)
+    }
+
+    /// The value of the Content-Type header to send along with the body.
+    pub fn content_type(&self) -> HeaderValue {
+        let value = format!("{}; boundary=\"{}\"", self.media_type, self.boundary);
+        // The media type was validated by `new` and boundaries are always valid.
+        HeaderValue::from_str(&value).expect("Content type is a valid header value")
+    }
+
+    /// Returns the Content-Type header value together with the encoded body.
+    pub fn into_parts(self) -> (HeaderValue, hyper::Body) {
+        (self.content_type(), self.into_body())
+    }
+
+    pub fn into_body(self) -> hyper::Body {
+        hyper::Body::wrap_stream(self.into_stream())
+    }
+
+    /// The encoded body as a stream of chunks.
+    pub fn into_stream(self) -> impl Stream<Item = Bytes, Error = Error> + Send {
+        let boundary = self.boundary;
+        let closing = closing_delimiter(&boundary);
+
+        stream::iter_ok::<_, Error>(self.parts.into_iter().enumerate())
+            .map(move |(index, part)| {
+                let head = stream::once(Ok(part_head(&boundary, &encode_headers(&part.headers))));
+                let body: BodyStream = match part.body {
+                    PartBody::Bytes(bs) => Box::new(stream::once(Ok(bs))),
+                    PartBody::Stream(s, None) => s,
+                    PartBody::Stream(s, Some(len)) => Box::new(SizedBody {
+                        inner: s,
+                        part: index,
+                        expected: len,
+                        actual: 0,
+                    }),
+                };
+                let tail = stream::once(Ok(Bytes::from_static(b"\r\n")));
+
+                head.chain(body).chain(tail)
+            })
+            .flatten()
+            .chain(stream::once(Ok(closing)))
+    }
+}
+
+/// Fails a streamed body that turns out to differ from its declared length.
+struct SizedBody {
+    inner: BodyStream,
+    part: usize,
+    expected: u64,
+    actual: u64,
+}
+
+impl Stream for SizedBody {
+    type Item = Bytes;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, Error> {
+        let item = try_ready!(self.inner.poll());
+
+        match item {
+            Some(ref chunk) => self.actual += chunk.len() as u64,
+            None if self.actual == self.expected => return Ok(Async::Ready(None)),
+            None => {}
+        }
+
+        if self.actual > self.expected || item.is_none() {
+            return Err(Error::LengthMismatch {
+                part: self.part,
+                expected: self.expected,
+                actual: self.actual,
+            });
+        }
+
+        Ok(Async::Ready(item))
+    }
+}
+
+impl EncoderPart {
+    fn collides(&self, boundary: &str) -> bool {
+        let in_body = match self.body {
+            PartBody::Bytes(ref bs) => boundary::collides(boundary, bs),
+            PartBody::Stream(..) => false,
+        };
+
+        in_body || boundary::collides(boundary, &encode_headers(&self.headers))
+    }
+}
+
+fn closing_delimiter(boundary: &str) -> Bytes {
+    Bytes::from(format!("--{}--\r\n", boundary))
+}
+
+/// The delimiter line and header section starting a part, up to and including
+/// the empty line that precedes the body.
+pub(crate) fn part_head(boundary: &str, headers: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(boundary.len() + headers.len() + 8);
+
+    buf.put_slice(b"--");
+    buf.put_slice(boundary.as_bytes());
+    buf.put_slice(b"\r\n");
+    if !headers.is_empty() {
+        buf.put_slice(headers);
+        buf.put_slice(b"\r\n");
+    }
+    buf.put_slice(b"\r\n");
+
+    buf.freeze()
+}
+
+fn encode_headers(headers: &HeaderMap<HeaderValue>) -> Vec<u8> {
+    encode_headers_with(headers, b": ")
+}
+
+/// Encodes a header section, without the blank line ending it.
+pub(crate) fn encode_headers_with(headers: &HeaderMap<HeaderValue>, separator: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::new();
+
+    for (name, value) in headers {
+        if !buf.is_empty() {
+            buf.extend_from_slice(b"\r\n");
+        }
+        buf.extend_from_slice(header_name(name).as_bytes());
+        buf.extend_from_slice(separator);
+        buf.extend_from_slice(value.as_bytes());
+    }
+
+    buf
+}
+
+/// Capitalizes header names, `content-type` becomes `Content-Type`.
+fn header_name(name: &HeaderName) -> String {
+    name.as_str()
+        .split('-')
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(c) => c.to_ascii_uppercase().to_string() + chars.as_str(),
+                None => String::new(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+fn file_headers(name: &str, filename: &str, content_type: &mime::Mime) -> HeaderMap<HeaderValue> {
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_DISPOSITION, disposition(name, Some(filename)));
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_str(content_type.as_ref()).expect("Mime is a valid header value"),
+    );
+    headers
+}
+
+fn disposition(name: &str, filename: Option<&str>) -> HeaderValue {
+    let mut value = format!("form-data; name=\"{}\"", escape_quoted(name));
+
+    if let Some(filename) = filename {
+        value.push_str(&format!("; filename=\"{}\"", escape_quoted(filename)));
+    }
+
+    // Non ASCII characters are sent as UTF-8, like browsers do.
+    HeaderValue::from_bytes(value.as_bytes()).expect("Escaped disposition is a valid header value")
+}
+
+/// Escapes a quoted string the way browsers do for form-data names, percent
+/// encoding quotes and control characters.
+fn escape_quoted(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        if c == '"' || c.is_ascii_control() {
+            escaped.push_str(&format!("%{:02X}", c as u8));
+        } else {
+            escaped.push(c);
+        }
+    }
+
+    escaped
+}
+
+/// Escapes a parameter value for use within quotes.
+fn escape_param(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{Multipart, Part};
+    use futures::Future;
+
+    fn encode(encoder: Encoder) -> Vec<Part> {
+        let (content_type, body) = encoder.into_parts();
+        let raw = body.concat2().wait().unwrap().into_bytes();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, content_type);
+
+        (headers, raw)
+            .into_multipart()
+            .unwrap()
+            .wait()
+            .map(|p| p.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn round_trip_through_parser() {
+        let mut json = HeaderMap::new();
+        json.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+
+        let chunks: Vec<Result<&'static [u8], String>> =
+            vec![Ok(b"streamed "), Ok(b"in "), Ok(b"chunks")];
+
+        let parts = encode(
+            Encoder::mixed()
+                .part(json, &b"{\"a\": 1}"[..])
+                .part(HeaderMap::new(), &b"no headers\r\n\r\nat all"[..])
+                .stream_part(HeaderMap::new(), stream::iter_result(chunks))
+                .file(
+                    "file",
+                    "snap \"1\".jpg",
+                    mime::IMAGE_JPEG,
+                    vec![0xff, 0xd8, 0x0d],
+                ),
+        );
+
+        assert_eq!(4, parts.len());
+
+        assert_eq!("application/json", parts[0].headers()["content-type"]);
+        assert_eq!(b"{\"a\": 1}", parts[0].body());
+
+        assert!(parts[1].headers().is_empty());
+        assert_eq!(&b"no headers\r\n\r\nat all"[..], parts[1].body());
+
+        assert_eq!(b"streamed in chunks", parts[2].body());
+
+        let headers = parts[3].headers();
+        assert_eq!("image/jpeg", headers["content-type"]);
+        assert_eq!(
+            "form-data; name=\"file\"; filename=\"snap %221%22.jpg\"",
+            headers["content-disposition"]
+        );
+        assert_eq!(&[0xff, 0xd8, 0x0d], parts[3].body());
+    }
+
+    #[test]
+    fn encode_form_data() {
+        let encoder = Encoder::form_data()
+            .with_boundary("b")
+            .unwrap()
+            .text("field", "value");
+        assert_eq!(
+            "multipart/form-data; boundary=\"b\"",
+            encoder.content_type()
+        );
+
+        let raw = encoder.into_body().concat2().wait().unwrap();
+        assert_eq!(
+            &b"--b\r\nContent-Disposition: form-data; name=\"field\"\r\n\r\nvalue\r\n--b--\r\n"[..],
+            &raw[..]
+        );
+    }
+
+    #[test]
+    fn regenerate_colliding_boundary() {
+        let encoder = Encoder::mixed();
+        let original = encoder.boundary().to_string();
+        let body = format!("\r\n--{}\r\n", original);
+
+        let encoder = encoder.part(HeaderMap::new(), body.clone());
+        assert_ne!(original, encoder.boundary());
+
+        let parts = encode(encoder);
+        assert_eq!(1, parts.len());
+        assert_eq!(body.as_bytes(), parts[0].body());
+    }
+
+    #[test]
+    fn keep_explicit_boundary() {
+        let encoder = Encoder::mixed()
+            .with_boundary("b")
+            .unwrap()
+            .part(HeaderMap::new(), "--b");
+        assert_eq!("b", encoder.boundary());
+    }
+
+    #[test]
+    fn reject_invalid_boundary() {
+        for boundary in &["a\nb", "a\"b", "", "trailing ", &"b".repeat(71)] {
+            match Encoder::mixed().with_boundary(*boundary) {
+                Err(Error::InvalidBoundary(b)) => assert_eq!(*boundary, b),
+                _ => panic!("Expected InvalidBoundary for {:?}", boundary),
+            }
+        }
+    }
+
+    #[test]
+    fn content_length_of_sized_parts() {
+        let chunks: Vec<Result<&'static [u8], String>> = vec![Ok(b"0123"), Ok(b"4567")];
+
+        let encoder = Encoder::form_data()
+            .text("field", "value")
+            .file_stream_with_len(
+                "file",
+                "f.bin",
+                mime::APPLICATION_OCTET_STREAM,
+                8,
+                stream::iter_result(chunks),
+            );
+
+        let len = encoder.content_length().expect("Length of sized parts");
+        let raw = encoder.into_body().concat2().wait().unwrap();
+        assert_eq!(raw.len() as u64, len);
+
+        let unsized_stream =
+            Encoder::mixed().stream_part(HeaderMap::new(), stream::empty::<Bytes, String>());
+        assert_eq!(None, unsized_stream.content_length());
+    }
+
+    #[test]
+    fn report_length_mismatch() {
+        let short = stream::iter_ok::<_, String>(vec![&b"0123"[..]]);
+        let res = Encoder::mixed()
+            .part(HeaderMap::new(), "first")
+            .stream_part_with_len(HeaderMap::new(), 8, short)
+            .into_stream()
+            .concat2()
+            .wait();
+
+        match res {
+            Err(Error::LengthMismatch {
+                part: 1,
+                expected: 8,
+                actual: 4,
+            }) => {}
+            other => panic!("Expected length mismatch, got {:?}", other.map(|_| ())),
+        }
+
+        let long = stream::iter_ok::<_, String>(vec![&b"0123"[..], &b"4567"[..]]);
+        let res = Encoder::mixed()
+            .stream_part_with_len(HeaderMap::new(), 6, long)
+            .into_stream()
+            .concat2()
+            .wait();
+
+        assert!(matches!(res, Err(Error::LengthMismatch { actual: 8, .. })));
+    }
+
+    #[test]
+    fn escape_control_characters_in_disposition() {
+        let encoder = Encoder::form_data().text("a\"b\r\n\0\t\x7f", "value").file(
+            "f",
+            "caf\u{e9}\x01.txt",
+            mime::TEXT_PLAIN,
+            "x",
+        );
+
+        let parts = encode(encoder);
+        assert_eq!(
+            &b"form-data; name=\"a%22b%0D%0A%00%09%7F\""[..],
+            parts[0].headers()["content-disposition"].as_bytes()
+        );
+        assert_eq!(
+            "form-data; name=\"f\"; filename=\"caf\u{e9}%01.txt\"".as_bytes(),
+            parts[1].headers()["content-disposition"].as_bytes()
+        );
+    }
+
+    #[test]
+    fn validate_subtype() {
+        for subtype in &["mixed\r\nX-Injected: 1", "a b", "a\"", "caf\u{e9}"] {
+            match Encoder::new(subtype) {
+                Err(Error::InvalidMimeType(_)) => {}
+                _ => panic!("Expected InvalidMimeType for {:?}", subtype),
+            }
+        }
+
+        let encoder = Encoder::new("related; type=\"application/json\"; boundary=b").unwrap();
+        assert_eq!("b", encoder.boundary());
+        assert_eq!(
+            "multipart/related; type=\"application/json\"; boundary=\"b\"",
+            encoder.content_type()
+        );
+
+        match Encoder::new("mixed; boundary=\"b \"") {
+            Err(Error::InvalidBoundary(_)) => {}
+            _ => panic!("Expected InvalidBoundary"),
+        }
+    }
+}
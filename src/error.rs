@@ -8,6 +8,39 @@ pub enum Error {
     MalformedMultipart(String),
     InvalidMimeType(mime::FromStrError),
     InnerStream(String),
+    /// A broadcast subscriber fell behind and was disconnected.
+    Lagged,
+    Io(std::io::Error),
+    /// A header line of a part could not be parsed.
+    InvalidHeader(String),
+    /// A part lacks a header that was asked for.
+    MissingHeader(String),
+    /// A boundary does not match the RFC 2046 grammar.
+    InvalidBoundary(String),
+    /// The body of part number `part` was `actual` bytes long, `expected` was declared.
+    LengthMismatch {
+        part: usize,
+        expected: u64,
+        actual: u64,
+    },
+    /// The Content-Transfer-Encoding of a part is not one of RFC 2045.
+    UnknownTransferEncoding(String),
+    /// A part body could not be decoded.
+    Decode(String),
+    /// The charset of a text part is not supported.
+    UnknownCharset(String),
+    /// A structured part could not be deserialized. `index` is its position in
+    /// the multipart body, if known, and `part` its form field name or else its
+    /// Content-ID, if it has one.
+    Deserialize {
+        index: Option<usize>,
+        part: Option<String>,
+        message: String,
+    },
+    /// The server answered with a status other than 2xx.
+    UnexpectedStatus(http::StatusCode),
+    /// No bytes were received for the given duration.
+    IdleTimeout(std::time::Duration),
 }
 
 impl Error {
@@ -30,6 +63,41 @@ impl fmt::Display for Error {
             }
             Error::InvalidMimeType(ref e) => write!(f, "Content-Type value invalid: {}", e),
             Error::InnerStream(ref e) => write!(f, "InnerStream: {}", e),
+            Error::Lagged => write!(f, "Subscriber lagged behind and was disconnected"),
+            Error::Io(ref e) => write!(f, "IO error: {}", e),
+            Error::InvalidHeader(ref line) => write!(f, "Invalid header line: {:?}", line),
+            Error::MissingHeader(ref name) => write!(f, "Missing header: {}", name),
+            Error::InvalidBoundary(ref boundary) => write!(f, "Invalid boundary: {:?}", boundary),
+            Error::LengthMismatch {
+                part,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Body of part {} was {} bytes, expected {}",
+                part, actual, expected
+            ),
+            Error::UnknownTransferEncoding(ref name) => {
+                write!(f, "Unknown Content-Transfer-Encoding: {:?}", name)
+            }
+            Error::Decode(ref msg) => write!(f, "Decoding part body failed: {}", msg),
+            Error::UnknownCharset(ref name) => write!(f, "Unsupported charset: {:?}", name),
+            Error::Deserialize {
+                index,
+                ref part,
+                ref message,
+            } => {
+                write!(f, "Deserializing part")?;
+                if let Some(index) = index {
+                    write!(f, " {}", index)?;
+                }
+                if let Some(ref name) = *part {
+                    write!(f, " {:?}", name)?;
+                }
+                write!(f, " failed: {}", message)
+            }
+            Error::UnexpectedStatus(status) => write!(f, "Unexpected response status: {}", status),
+            Error::IdleTimeout(timeout) => write!(f, "No data received for {:?}", timeout),
         }
     }
 }
@@ -44,6 +112,18 @@ impl StdError for Error {
                 "Value of the Content Type header contained an invalid mime type"
             }
             Error::InnerStream(_) => "Http error thrown by the underlying layer",
+            Error::Lagged => "Subscriber could not keep up with the stream",
+            Error::Io(_) => "Reading or writing a file failed",
+            Error::InvalidHeader(_) => "A part header line could not be parsed",
+            Error::MissingHeader(_) => "A part lacks an expected header",
+            Error::InvalidBoundary(_) => "A boundary contains invalid characters or is too long",
+            Error::LengthMismatch { .. } => "Part body length differs from the declared length",
+            Error::UnknownTransferEncoding(_) => "Part uses an unknown Content-Transfer-Encoding",
+            Error::Decode(_) => "Part body could not be decoded",
+            Error::UnknownCharset(_) => "Part uses an unsupported charset",
+            Error::Deserialize { .. } => "Part could not be deserialized",
+            Error::UnexpectedStatus(_) => "The http response status was not successful",
+            Error::IdleTimeout(_) => "The connection stalled",
         }
     }
 
@@ -52,6 +132,7 @@ impl StdError for Error {
             Error::MalformedMultipart(_) => None,
             Error::InvalidMimeType(ref e) => Some(e),
             Error::InnerStream(_) => None,
+            Error::Io(ref e) => Some(e),
             _ => None,
         }
     }
@@ -62,3 +143,9 @@ impl From<hyper::Error> for Error {
         Error::InnerStream(format!("Hyper error: {}", inner))
     }
 }
+
+impl From<std::io::Error> for Error {
+    fn from(inner: std::io::Error) -> Self {
+        Error::Io(inner)
+    }
+}
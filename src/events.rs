@@ -0,0 +1,473 @@
+use crate::{parser::boundary_from_headers, part::parse_headers, Error, HeaderSyntax};
+use bytes::{Bytes, BytesMut};
+use futures::{Async, Poll, Stream};
+use http::header::{HeaderMap, HeaderValue};
+
+/// Default limit of the size of the header section of a part.
+pub const DEFAULT_MAX_HEADER_SIZE: usize = 16 * 1024;
+
+/// A single step of a multipart body.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// A new part begins, with its headers.
+    PartStart(HeaderMap<HeaderValue>),
+    /// A piece of the body of the current part.
+    BodyChunk(Bytes),
+    /// The current part is complete.
+    PartEnd,
+    /// The closing delimiter was found, no more parts follow.
+    End,
+}
+
+#[derive(Debug)]
+pub enum EventResult {
+    NotReady,
+    Ready(Event),
+    Err(Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Preamble,
+    Delimiter,
+    Headers,
+    Body,
+    Done,
+}
+
+/// Sans-IO multipart parser emitting `Event`s as soon as they are available.
+/// Unlike `BoundaryParser` it never buffers an entire part, body bytes are
+/// handed out as soon as they cannot be the start of a delimiter.
+#[derive(Debug)]
+pub struct EventParser {
+    /// The delimiter including its leading CRLF, `\r\n--boundary`.
+    delimiter: Vec<u8>,
+    buffer: BytesMut,
+    state: State,
+    /// No more bytes will be added.
+    end_of_input: bool,
+    header_syntax: HeaderSyntax,
+    max_header_size: usize,
+}
+
+impl EventParser {
+    pub fn with_capacity<S: AsRef<str>>(boundary: S, capacity: usize) -> Self {
+        Self {
+            delimiter: format!("\r\n--{}", boundary.as_ref()).into_bytes(),
+            buffer: BytesMut::with_capacity(capacity),
+            state: State::Preamble,
+            end_of_input: false,
+            header_syntax: HeaderSyntax::default(),
+            max_header_size: DEFAULT_MAX_HEADER_SIZE,
+        }
+    }
+
+    /// Fails with `MalformedMultipart` when the header section of a part,
+    /// without the blank line ending it, is larger than `bytes`. Defaults to
+    /// `DEFAULT_MAX_HEADER_SIZE`.
+    pub fn with_max_header_size(mut self, bytes: usize) -> Self {
+        self.max_header_size = bytes;
+        self
+    }
+
+    /// Sets the syntax the headers of `Event::PartStart` are parsed with.
+    pub fn with_header_syntax(mut self, syntax: HeaderSyntax) -> Self {
+        self.header_syntax = syntax;
+        self
+    }
+
+    pub fn add_bytes<T: AsRef<[u8]>>(&mut self, bs: T) {
+        self.buffer.extend(bs.as_ref())
+    }
+
+    /// Tells the parser no more bytes will be added, so a closing delimiter
+    /// at the very end of the input is not waited on for its CRLF.
+    pub fn end_of_input(&mut self) {
+        self.end_of_input = true;
+    }
+
+    /// Returns the bytes not consumed by the parser. After `Event::End` these
+    /// are the bytes following the closing delimiter line.
+    pub fn into_buffer(self) -> Bytes {
+        self.buffer.freeze()
+    }
+
+    pub fn parse(&mut self) -> EventResult {
+        const CRLF: &[u8] = b"\r\n";
+
+        loop {
+            match self.state {
+                State::Preamble => {
+                    // The first delimiter does not need the leading CRLF.
+                    let delimiter = &self.delimiter[2..];
+
+                    match twoway::find_bytes(&self.buffer, delimiter) {
+                        Some(i) => {
+                            self.buffer.advance(i + delimiter.len());
+                            self.state = State::Delimiter;
+                        }
+                        None => {
+                            // Discard the preamble, except what could be the
+                            // start of the delimiter.
+                            let keep = delimiter.len() - 1;
+                            if self.buffer.len() > keep {
+                                let discard = self.buffer.len() - keep;
+                                self.buffer.advance(discard);
+                            }
+                            return EventResult::NotReady;
+                        }
+                    }
+                }
+
+                State::Delimiter => {
+                    if self.buffer.len() < 2 {
+                        return EventResult::NotReady;
+                    }
+
+                    match &self.buffer[..2] {
+                        CRLF => {
+                            self.buffer.advance(2);
+                            self.state = State::Headers;
+                        }
+
+                        b"--" => {
+                            // Wait for the CRLF ending the line unless the
+                            // input ended.
+                            let rest = &self.buffer[2..];
+                            if !self.end_of_input && rest.len() < 2 && CRLF.starts_with(rest) {
+                                return EventResult::NotReady;
+                            }
+
+                            let line_end = if rest.starts_with(CRLF) { 4 } else { 2 };
+                            self.buffer.advance(line_end);
+
+                            self.state = State::Done;
+                            return EventResult::Ready(Event::End);
+                        }
+
+                        slice => {
+                            return EventResult::Err(Error::malformed(format!(
+                                "Boundary must be followed by `--` or `\r\n`, found: {:?}",
+                                slice
+                            )));
+                        }
+                    }
+                }
+
+                State::Headers => {
+                    if self.buffer.starts_with(CRLF) {
+                        self.buffer.advance(2);
+                        self.state = State::Body;
+                        return EventResult::Ready(Event::PartStart(HeaderMap::new()));
+                    }
+
+                    match twoway::find_bytes(&self.buffer, b"\r\n\r\n") {
+                        Some(i) if i > self.max_header_size => {
+                            return EventResult::Err(self.headers_too_large());
+                        }
+                        Some(i) => {
+                            let headers = self.buffer.split_to(i);
+                            self.buffer.advance(4);
+                            self.state = State::Body;
+                            return EventResult::Ready(Event::PartStart(parse_headers(
+                                &headers,
+                                self.header_syntax,
+                            )));
+                        }
+                        // The blank line could still start in the last 3 bytes.
+                        None if self.buffer.len() > self.max_header_size + 3 => {
+                            return EventResult::Err(self.headers_too_large());
+                        }
+                        None => return EventResult::NotReady,
+                    }
+                }
+
+                State::Body => match twoway::find_bytes(&self.buffer, &self.delimiter) {
+                    Some(0) => {
+                        self.buffer.advance(self.delimiter.len());
+                        self.state = State::Delimiter;
+                        return EventResult::Ready(Event::PartEnd);
+                    }
+                    Some(i) => {
+                        let chunk = self.buffer.split_to(i).freeze();
+                        return EventResult::Ready(Event::BodyChunk(chunk));
+                    }
+                    None => {
+                        let keep = self.delimiter.len() - 1;
+                        if self.buffer.len() <= keep {
+                            return EventResult::NotReady;
+                        }
+
+                        let n = self.buffer.len() - keep;
+                        let chunk = self.buffer.split_to(n).freeze();
+                        return EventResult::Ready(Event::BodyChunk(chunk));
+                    }
+                },
+
+                State::Done => return EventResult::Ready(Event::End),
+            }
+        }
+    }
+
+    fn headers_too_large(&self) -> Error {
+        Error::malformed(format!(
+            "Part headers exceed the limit of {} bytes",
+            self.max_header_size
+        ))
+    }
+}
+
+/// Stream of `Event`s parsed from a stream of bytes.
+pub struct Events<S> {
+    inner: S,
+    parser: EventParser,
+    inner_done: bool,
+    ended: bool,
+}
+
+impl<S, I, E> Events<S>
+where
+    S: Stream<Item = I, Error = E>,
+    I: AsRef<[u8]>,
+    E: std::fmt::Display + Send + 'static,
+{
+    /// Parses `stream` with the boundary of the multipart Content-Type in `headers`.
+    pub fn new<H: crate::HeaderMap>(headers: &H, stream: S) -> Result<Self, Error> {
+        let boundary = boundary_from_headers(headers)?;
+        Ok(Self::with_boundary(boundary, stream))
+    }
+
+    pub fn with_boundary<B: AsRef<str>>(boundary: B, stream: S) -> Self {
+        Self {
+            inner: stream,
+            parser: EventParser::with_capacity(boundary, crate::multipart::DEFAULT_BUFFER_CAP),
+            inner_done: false,
+            ended: false,
+        }
+    }
+
+    /// Sets the syntax the headers of `Event::PartStart` are parsed with, for
+    /// streams separating header names and values with something else than ':'.
+    pub fn with_header_syntax(mut self, syntax: HeaderSyntax) -> Self {
+        self.parser = self.parser.with_header_syntax(syntax);
+        self
+    }
+
+    /// Limits the size of the header section of a part, see
+    /// `EventParser::with_max_header_size`.
+    pub fn with_max_header_size(mut self, bytes: usize) -> Self {
+        self.parser = self.parser.with_max_header_size(bytes);
+        self
+    }
+
+    /// Returns the bytes buffered but not consumed by the parser, together with
+    /// the inner stream.
+    pub fn into_inner(self) -> (Bytes, S) {
+        (self.parser.into_buffer(), self.inner)
+    }
+}
+
+impl<S, I, E> Stream for Events<S>
+where
+    S: Stream<Item = I, Error = E>,
+    I: AsRef<[u8]>,
+    E: std::fmt::Display + Send + 'static,
+{
+    type Item = Event;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Event>, Error> {
+        if self.ended {
+            return Ok(Async::Ready(None));
+        }
+
+        loop {
+            match self.parser.parse() {
+                EventResult::Ready(event) => {
+                    self.ended = event == Event::End;
+                    return Ok(Async::Ready(Some(event)));
+                }
+                EventResult::Err(e) => return Err(e),
+                EventResult::NotReady if self.inner_done => {
+                    return Err(Error::malformed("Unexpected end to multipart stream"));
+                }
+                EventResult::NotReady => {}
+            }
+
+            match self.inner.poll() {
+                Ok(Async::Ready(Some(chunk))) => self.parser.add_bytes(chunk),
+                Ok(Async::Ready(None)) => {
+                    self.inner_done = true;
+                    self.parser.end_of_input();
+                }
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(e) => return Err(Error::inner(e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    const BODY: &str = "preamble\r
+--simple boundary\r
+Content-Type: text/plain\r
+\r
+Part1\r
+--simple boundary\r
+\r
+Part2 with\r
+a line break\r
+--simple boundary--\r
+epilogue";
+
+    fn events(chunk_size: usize) -> Vec<Event> {
+        let chunks: Vec<Result<&[u8], String>> =
+            BODY.as_bytes().chunks(chunk_size).map(Ok).collect();
+
+        Events::with_boundary("simple boundary", futures::stream::iter_result(chunks))
+            .wait()
+            .map(|e| e.unwrap())
+            .collect()
+    }
+
+    /// Joins consecutive body chunks, so results are comparable across chunk sizes.
+    fn joined(events: Vec<Event>) -> Vec<Event> {
+        let mut res: Vec<Event> = Vec::new();
+
+        for event in events {
+            match (res.last_mut(), event) {
+                (Some(Event::BodyChunk(ref mut acc)), Event::BodyChunk(bs)) => {
+                    let mut joined = acc.to_vec();
+                    joined.extend_from_slice(&bs);
+                    *acc = joined.into();
+                }
+                (_, event) => res.push(event),
+            }
+        }
+
+        res
+    }
+
+    #[test]
+    fn parse_events() {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", HeaderValue::from_static("text/plain"));
+
+        let exp = vec![
+            Event::PartStart(headers),
+            Event::BodyChunk("Part1".into()),
+            Event::PartEnd,
+            Event::PartStart(HeaderMap::new()),
+            Event::BodyChunk("Part2 with\r\na line break".into()),
+            Event::PartEnd,
+            Event::End,
+        ];
+
+        assert_eq!(exp, events(BODY.len()));
+
+        for chunk_size in 1..20 {
+            assert_eq!(exp, joined(events(chunk_size)), "chunk size {}", chunk_size);
+        }
+    }
+
+    #[test]
+    fn body_chunks_are_emitted_before_part_end() {
+        let mut p = EventParser::with_capacity("b", 100);
+        p.add_bytes("--b\r\n\r\n0123456789");
+
+        match p.parse() {
+            EventResult::Ready(Event::PartStart(_)) => {}
+            other => panic!("Expected PartStart, got {:?}", other),
+        }
+
+        // All but the bytes that could start a delimiter are handed out.
+        match p.parse() {
+            EventResult::Ready(Event::BodyChunk(bs)) => assert_eq!(&b"012345"[..], &bs[..]),
+            other => panic!("Expected BodyChunk, got {:?}", other),
+        }
+
+        match p.parse() {
+            EventResult::NotReady => {}
+            other => panic!("Expected NotReady, got {:?}", other),
+        }
+
+        p.add_bytes("\r\n--b--\r\nrest");
+        match p.parse() {
+            EventResult::Ready(Event::BodyChunk(bs)) => assert_eq!(&b"6789"[..], &bs[..]),
+            other => panic!("Expected BodyChunk, got {:?}", other),
+        }
+
+        assert!(matches!(p.parse(), EventResult::Ready(Event::PartEnd)));
+        assert!(matches!(p.parse(), EventResult::Ready(Event::End)));
+        assert_eq!(&b"rest"[..], &p.into_buffer()[..]);
+    }
+
+    #[test]
+    fn parse_events_with_header_syntax() {
+        let chunks: Vec<Result<&[u8], String>> = vec![Ok(
+            b"--b\r\nContent-Type=image/jpeg\r\n\r\njpeg\r\n--b--\r\n",
+        )];
+
+        let start = Events::with_boundary("b", futures::stream::iter_result(chunks))
+            .with_header_syntax(HeaderSyntax::Equals)
+            .wait()
+            .next()
+            .unwrap()
+            .unwrap();
+
+        match start {
+            Event::PartStart(headers) => assert_eq!("image/jpeg", headers["content-type"]),
+            other => panic!("Expected PartStart, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn closing_delimiter_split_across_chunks() {
+        let chunks: Vec<Result<&[u8], String>> =
+            vec![Ok(b"--b\r\n\r\nP1\r\n--b"), Ok(b"--"), Ok(b"\r\nNEXT")];
+        let mut events = Events::with_boundary("b", futures::stream::iter_result(chunks));
+
+        let all: Vec<Event> = events.by_ref().wait().map(|e| e.unwrap()).collect();
+        assert_eq!(Some(&Event::End), all.last());
+        assert_eq!(&b"NEXT"[..], &events.into_inner().0[..]);
+
+        // The input may end right after the closing delimiter.
+        let chunks: Vec<Result<&[u8], String>> = vec![Ok(b"--b\r\n\r\nP1\r\n--b--")];
+        let events = Events::with_boundary("b", futures::stream::iter_result(chunks));
+        let all: Vec<Event> = events.wait().map(|e| e.unwrap()).collect();
+        assert_eq!(Some(&Event::End), all.last());
+    }
+
+    #[test]
+    fn header_size_is_limited() {
+        let head = "--b\r\nContent-Type: text/plain\r\n";
+        let headers_len = head.len() - "--b\r\n".len() - 2;
+
+        let mut p = EventParser::with_capacity("b", 100).with_max_header_size(headers_len);
+        p.add_bytes(format!("{}\r\nbody\r\n--b--\r\n", head));
+        assert!(matches!(p.parse(), EventResult::Ready(Event::PartStart(_))));
+
+        let mut p = EventParser::with_capacity("b", 100).with_max_header_size(headers_len - 1);
+        p.add_bytes(head);
+        assert!(matches!(p.parse(), EventResult::NotReady));
+        p.add_bytes("\r\nbody");
+        assert!(matches!(
+            p.parse(),
+            EventResult::Err(Error::MalformedMultipart(_))
+        ));
+
+        // Without an end of the headers in sight.
+        let mut p = EventParser::with_capacity("b", 100).with_max_header_size(16);
+        p.add_bytes("--b\r\nX-Long: ");
+        assert!(matches!(p.parse(), EventResult::NotReady));
+        p.add_bytes(vec![b'a'; 1024]);
+        assert!(matches!(
+            p.parse(),
+            EventResult::Err(Error::MalformedMultipart(_))
+        ));
+    }
+}
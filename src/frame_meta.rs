@@ -0,0 +1,152 @@
+use crate::Error;
+use http::header::{HeaderMap, HeaderValue, CONTENT_LENGTH, CONTENT_TYPE};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Capture time of the frame, seconds since the unix epoch.
+const TIMESTAMP: &str = "x-timestamp";
+/// Time the camera sent the frame, seconds since the unix epoch.
+const SEND_TIMESTAMP: &str = "x-sendtimestamp";
+/// Milliseconds between capture and send.
+const TIME_DIFF: &str = "x-timediff";
+
+/// Typed access to the metadata headers IP cameras put on each frame of an
+/// MJPEG stream. Every accessor fails with `Error::MissingHeader` when the
+/// header is absent and `Error::InvalidHeader` when it cannot be parsed.
+#[derive(Clone, Debug)]
+pub struct FrameMeta {
+    headers: HeaderMap<HeaderValue>,
+}
+
+impl FrameMeta {
+    pub fn new(headers: HeaderMap<HeaderValue>) -> Self {
+        FrameMeta { headers }
+    }
+
+    /// When the frame was captured, from `X-Timestamp`.
+    pub fn timestamp(&self) -> Result<SystemTime, Error> {
+        self.get(TIMESTAMP)
+            .and_then(|v| parse_timestamp(TIMESTAMP, v))
+    }
+
+    /// When the camera sent the frame, from `X-SendTimestamp`.
+    pub fn send_timestamp(&self) -> Result<SystemTime, Error> {
+        self.get(SEND_TIMESTAMP)
+            .and_then(|v| parse_timestamp(SEND_TIMESTAMP, v))
+    }
+
+    /// Time between capture and send, from `X-TimeDiff`.
+    pub fn time_diff(&self) -> Result<Duration, Error> {
+        let value = self.get(TIME_DIFF)?;
+        value
+            .parse()
+            .map(Duration::from_millis)
+            .map_err(|_| invalid(TIME_DIFF, value))
+    }
+
+    pub fn content_length(&self) -> Result<u64, Error> {
+        let value = self.get(CONTENT_LENGTH.as_str())?;
+        value
+            .parse()
+            .map_err(|_| invalid(CONTENT_LENGTH.as_str(), value))
+    }
+
+    pub fn content_type(&self) -> Result<mime::Mime, Error> {
+        self.get(CONTENT_TYPE.as_str())?
+            .parse()
+            .map_err(Error::InvalidMimeType)
+    }
+
+    fn get(&self, name: &str) -> Result<&str, Error> {
+        let value = self
+            .headers
+            .get(name)
+            .ok_or_else(|| Error::MissingHeader(name.to_string()))?;
+
+        value
+            .to_str()
+            .map(str::trim)
+            .map_err(|_| Error::InvalidHeader(format!("{}: {:?}", name, value)))
+    }
+}
+
+fn invalid(name: &str, value: &str) -> Error {
+    Error::InvalidHeader(format!("{}: {}", name, value))
+}
+
+/// Parses seconds since the unix epoch with an optional fraction, such as
+/// `1550567095.266`, without going through a float.
+fn parse_timestamp(name: &str, value: &str) -> Result<SystemTime, Error> {
+    let (secs, fraction) = match value.find('.') {
+        Some(i) => (&value[..i], &value[i + 1..]),
+        None => (value, ""),
+    };
+
+    let is_digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+    if secs.is_empty() || !is_digits(secs) || !is_digits(fraction) {
+        return Err(invalid(name, value));
+    }
+
+    let secs: u64 = secs.parse().map_err(|_| invalid(name, value))?;
+    // Nanoseconds from at most 9 fraction digits, padded with zeros.
+    let nanos = fraction
+        .bytes()
+        .chain(std::iter::repeat(b'0'))
+        .take(9)
+        .fold(0u32, |n, b| n * 10 + u32::from(b - b'0'));
+
+    UNIX_EPOCH
+        .checked_add(Duration::new(secs, nanos))
+        .ok_or_else(|| invalid(name, value))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::Part;
+
+    #[test]
+    fn camera_headers() {
+        let meta = Part::from(
+            &b"Content-Type: image/jpeg\r\n\
+Content-Length: 4\r\n\
+X-Timestamp: 1550567095.266\r\n\
+X-SendTimestamp: 1550567095.439\r\n\
+X-TimeDiff: 173\r\n\r\njpeg"[..],
+        )
+        .frame_meta();
+
+        assert_eq!(
+            UNIX_EPOCH + Duration::from_millis(1_550_567_095_266),
+            meta.timestamp().unwrap()
+        );
+        assert_eq!(
+            UNIX_EPOCH + Duration::from_millis(1_550_567_095_439),
+            meta.send_timestamp().unwrap()
+        );
+        assert_eq!(Duration::from_millis(173), meta.time_diff().unwrap());
+        assert_eq!(4, meta.content_length().unwrap());
+        assert_eq!(mime::IMAGE_JPEG, meta.content_type().unwrap());
+    }
+
+    #[test]
+    fn missing_and_malformed_headers() {
+        let meta =
+            Part::from(&b"X-Timestamp: yesterday\r\nContent-Length: -1\r\n\r\n"[..]).frame_meta();
+
+        assert!(matches!(meta.timestamp(), Err(Error::InvalidHeader(_))));
+        assert!(matches!(
+            meta.content_length(),
+            Err(Error::InvalidHeader(_))
+        ));
+        match meta.send_timestamp() {
+            Err(Error::MissingHeader(name)) => assert_eq!("x-sendtimestamp", name),
+            other => panic!("Expected MissingHeader, got {:?}", other),
+        }
+
+        for bad in &["", ".5", "1.5.", "1e9", "+1"] {
+            assert!(parse_timestamp(TIMESTAMP, bad).is_err(), "{:?}", bad);
+        }
+        assert_eq!(UNIX_EPOCH, parse_timestamp(TIMESTAMP, "0.").unwrap());
+    }
+}
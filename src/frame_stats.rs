@@ -0,0 +1,342 @@
+use crate::{Error, Part};
+use futures::{Async, Stream};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Default number of frames the rolling statistics are calculated over.
+pub const DEFAULT_WINDOW: usize = 100;
+
+/// Stream adapter keeping rolling statistics of the parts passing through it,
+/// such as frame rate, inter-frame jitter and frame sizes. If the parts carry an
+/// `X-Timestamp` or `X-SendTimestamp` header the camera to receiver latency and
+/// clock drift is reported as well.
+pub struct FrameStats<S> {
+    inner: S,
+    handle: FrameStatsHandle,
+}
+
+/// Cloneable handle to the statistics of a `FrameStats` stream. All clones
+/// share the same window, so a handle can be kept to read reports after the
+/// stream has been moved into a task or combinator.
+#[derive(Clone, Debug)]
+pub struct FrameStatsHandle {
+    inner: Arc<Mutex<Window>>,
+}
+
+#[derive(Debug)]
+struct Window {
+    size: usize,
+    frames: u64,
+    samples: VecDeque<Sample>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    arrived: Instant,
+    size: usize,
+    /// Receive time minus capture time, in seconds.
+    latency: Option<f64>,
+    /// Receive time minus send time, in seconds.
+    send_latency: Option<f64>,
+}
+
+/// A snapshot of the statistics kept by `FrameStats`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct FrameReport {
+    /// Total number of frames seen.
+    pub frames: u64,
+    /// Frames per second over the window.
+    pub fps: f64,
+    /// Standard deviation of the time between frames, in seconds.
+    pub jitter: f64,
+    pub min_size: usize,
+    pub max_size: usize,
+    pub mean_size: f64,
+    pub median_size: usize,
+    /// Mean time between capture and arrival, in seconds. Only available when
+    /// the frames carry an `X-Timestamp` header.
+    pub latency: Option<f64>,
+    /// Mean time between the camera sending a frame and its arrival, in
+    /// seconds. Only available when the frames carry an `X-SendTimestamp`
+    /// header.
+    pub send_latency: Option<f64>,
+    /// How fast the latency is changing, in seconds per second. A non zero value
+    /// means the camera clock runs faster or slower than the local clock. Taken
+    /// from the capture latency if available, otherwise the send latency.
+    pub clock_drift: Option<f64>,
+}
+
+impl<S> FrameStats<S>
+where
+    S: Stream<Item = Part, Error = Error>,
+{
+    pub fn new(inner: S) -> Self {
+        Self::with_window(inner, DEFAULT_WINDOW)
+    }
+
+    /// Keep statistics over the last `window` frames.
+    pub fn with_window(inner: S, window: usize) -> Self {
+        let window = window.max(2);
+        Self {
+            inner,
+            handle: FrameStatsHandle {
+                inner: Arc::new(Mutex::new(Window {
+                    size: window,
+                    frames: 0,
+                    samples: VecDeque::with_capacity(window),
+                })),
+            },
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// A handle to read reports from, also after the stream has been moved.
+    pub fn handle(&self) -> FrameStatsHandle {
+        self.handle.clone()
+    }
+
+    pub fn report(&self) -> FrameReport {
+        self.handle.report()
+    }
+
+    fn record(&self, part: &Part, arrived: Instant, arrived_wall: SystemTime) {
+        self.handle.lock().record(part, arrived, arrived_wall);
+    }
+}
+
+impl FrameStatsHandle {
+    pub fn report(&self) -> FrameReport {
+        self.lock().report()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Window> {
+        // The window is always left consistent, a panic elsewhere does not
+        // make it unusable.
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Window {
+    fn report(&self) -> FrameReport {
+        let mut report = FrameReport {
+            frames: self.frames,
+            ..FrameReport::default()
+        };
+
+        if self.samples.is_empty() {
+            return report;
+        }
+
+        let mut sizes: Vec<usize> = self.samples.iter().map(|s| s.size).collect();
+        sizes.sort_unstable();
+        report.min_size = sizes[0];
+        report.max_size = sizes[sizes.len() - 1];
+        report.median_size = sizes[sizes.len() / 2];
+        report.mean_size = sizes.iter().sum::<usize>() as f64 / sizes.len() as f64;
+
+        let intervals: Vec<f64> = self
+            .samples
+            .iter()
+            .zip(self.samples.iter().skip(1))
+            .map(|(a, b)| secs(b.arrived.duration_since(a.arrived)))
+            .collect();
+
+        if !intervals.is_empty() {
+            let total: f64 = intervals.iter().sum();
+            let mean = total / intervals.len() as f64;
+
+            if total > 0.0 {
+                report.fps = intervals.len() as f64 / total;
+            }
+
+            let variance =
+                intervals.iter().map(|i| (i - mean).powi(2)).sum::<f64>() / intervals.len() as f64;
+            report.jitter = variance.sqrt();
+        }
+
+        let latencies = |f: fn(&Sample) -> Option<f64>| -> Vec<(Instant, f64)> {
+            self.samples
+                .iter()
+                .filter_map(|s| f(s).map(|l| (s.arrived, l)))
+                .collect()
+        };
+        let mean = |ls: &[(Instant, f64)]| {
+            if ls.is_empty() {
+                None
+            } else {
+                Some(ls.iter().map(|(_, l)| l).sum::<f64>() / ls.len() as f64)
+            }
+        };
+
+        let capture = latencies(|s| s.latency);
+        let send = latencies(|s| s.send_latency);
+        report.latency = mean(&capture);
+        report.send_latency = mean(&send);
+
+        let latencies = if capture.is_empty() { send } else { capture };
+        if latencies.len() > 1 {
+            let (first_at, first) = latencies[0];
+            let (last_at, last) = latencies[latencies.len() - 1];
+            let elapsed = secs(last_at.duration_since(first_at));
+
+            if elapsed > 0.0 {
+                report.clock_drift = Some((last - first) / elapsed);
+            }
+        }
+
+        report
+    }
+
+    fn record(&mut self, part: &Part, arrived: Instant, arrived_wall: SystemTime) {
+        let received = secs(arrived_wall.duration_since(UNIX_EPOCH).unwrap_or_default());
+        let meta = part.frame_meta();
+        let since = |t: SystemTime| {
+            t.duration_since(UNIX_EPOCH)
+                .ok()
+                .map(|t| received - secs(t))
+        };
+
+        let latency = meta.timestamp().ok().and_then(since);
+        let send_latency = meta.send_timestamp().ok().and_then(since);
+
+        if self.samples.len() == self.size {
+            self.samples.pop_front();
+        }
+
+        self.frames += 1;
+        self.samples.push_back(Sample {
+            arrived,
+            size: part.body_len(),
+            latency,
+            send_latency,
+        });
+    }
+}
+
+impl<S> Stream for FrameStats<S>
+where
+    S: Stream<Item = Part, Error = Error>,
+{
+    type Item = Part;
+    type Error = Error;
+
+    fn poll(&mut self) -> Result<Async<Option<Self::Item>>, Self::Error> {
+        match self.inner.poll()? {
+            Async::Ready(Some(part)) => {
+                let (arrived, arrived_at) = part
+                    .origin()
+                    .map_or((Instant::now(), SystemTime::now()), |o| {
+                        (o.arrived, o.arrived_at)
+                    });
+                self.record(&part, arrived, arrived_at);
+                Ok(Async::Ready(Some(part)))
+            }
+
+            other => Ok(other),
+        }
+    }
+}
+
+/// Capture time from the `X-Timestamp` header, in seconds since the unix epoch.
+pub(crate) fn capture_time(part: &Part) -> Option<f64> {
+    let captured = part.frame_meta().timestamp().ok()?;
+    captured.duration_since(UNIX_EPOCH).ok().map(secs)
+}
+
+fn secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + f64::from(d.subsec_nanos()) / 1e9
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use futures::Future;
+
+    fn frame(captured: f64, size: usize) -> Part {
+        let mut data = format!("X-Timestamp: {}\r\n\r\n", captured).into_bytes();
+        data.resize(data.len() + size, 0);
+        Part::from(&data[..])
+    }
+
+    fn at(secs: f64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis((secs * 1000.0) as u64)
+    }
+
+    #[test]
+    fn report_rolling_statistics() {
+        let stats = FrameStats::with_window(futures::stream::empty(), 4);
+        let start = Instant::now();
+
+        // Ten frames per second, every frame 0.5s late and the camera clock
+        // falling behind 10ms per frame.
+        for i in 0..5u64 {
+            let offset = Duration::from_millis(100 * i);
+            let received = 1000.0 + 0.1 * i as f64;
+            let captured = received - 0.5 - 0.01 * i as f64;
+            let part = frame(captured, 100 * (i as usize + 1));
+
+            stats.record(&part, start + offset, at(received));
+        }
+
+        let report = stats.report();
+
+        assert_eq!(5, report.frames);
+        assert!((report.fps - 10.0).abs() < 1e-6);
+        assert!(report.jitter < 1e-6);
+        assert_eq!(200, report.min_size);
+        assert_eq!(500, report.max_size);
+        assert_eq!(400, report.median_size);
+        assert!((report.mean_size - 350.0).abs() < 1e-6);
+
+        let latency = report.latency.expect("Latency missing");
+        assert!((latency - 0.525).abs() < 1e-3);
+
+        let drift = report.clock_drift.expect("Clock drift missing");
+        assert!((drift - 0.1).abs() < 1e-2);
+    }
+
+    #[test]
+    fn report_without_timestamps() {
+        let stats = FrameStats::new(futures::stream::empty());
+        let start = Instant::now();
+
+        for i in 0..3 {
+            let part = Part::from(&b"\r\n\r\nbody"[..]);
+            stats.record(&part, start + Duration::from_millis(40 * i), at(0.0));
+        }
+
+        let report = stats.report();
+        assert_eq!(3, report.frames);
+        assert!((report.fps - 25.0).abs() < 1e-6);
+        assert_eq!(None, report.latency);
+        assert_eq!(None, report.send_latency);
+        assert_eq!(None, report.clock_drift);
+    }
+
+    #[test]
+    fn handle_reports_after_stream_is_moved() {
+        let parts: Vec<Part> = (0..3)
+            .map(|i| {
+                let sent = 1000.0 + f64::from(i);
+                Part::from(format!("X-SendTimestamp: {}\r\n\r\nbody", sent).as_bytes())
+            })
+            .collect();
+        let stats = FrameStats::new(futures::stream::iter_ok::<_, Error>(parts));
+        let handle = stats.handle();
+
+        assert_eq!(3, stats.collect().wait().unwrap().len());
+
+        let report = handle.report();
+        assert_eq!(3, report.frames);
+        assert_eq!(None, report.latency);
+        let latency = report.send_latency.expect("Send latency missing");
+        assert!(latency > 0.0);
+    }
+}
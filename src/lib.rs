@@ -2,13 +2,56 @@ mod error;
 pub use error::Error;
 
 mod multipart;
-pub use multipart::{Multipart, MultipartChunks};
+pub use multipart::{Buffered, LengthCheck, Multipart, MultipartChunks};
 
 mod part;
-pub use part::Part;
+pub use part::{HeaderSyntax, Origin, Part};
+
+mod disposition;
+pub use disposition::ContentDisposition;
+
+pub mod transfer_encoding;
+pub use transfer_encoding::TransferEncoding;
+
+mod charset;
+pub use charset::Charset;
 
 pub mod parser;
 
+pub mod events;
+pub use events::{Event, Events};
+
+pub mod boundary;
+
+mod encoder;
+pub use encoder::Encoder;
+
+pub mod broadcast;
+pub use broadcast::{Broadcast, BroadcastHandle, Lagging, Subscriber};
+
+pub mod record;
+pub use record::{Recorder, Recording};
+
+pub mod byteranges;
+
+pub mod serve;
+pub use serve::MixedReplace;
+
+pub mod reconnect;
+pub use reconnect::Reconnect;
+
+pub mod replay;
+pub use replay::{Pace, Replay};
+
+mod stats;
+pub use stats::Stats;
+
+pub mod frame_stats;
+pub use frame_stats::{FrameReport, FrameStats, FrameStatsHandle};
+
+mod frame_meta;
+pub use frame_meta::FrameMeta;
+
 mod header_map;
 pub use header_map::HeaderMap;
 
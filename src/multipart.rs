@@ -1,8 +1,13 @@
 use crate::{
     parser::{ParseResult, Parser},
-    Part,
+    Charset, HeaderSyntax, Origin, Part, Stats,
 };
+use bytes::Bytes;
 use futures::{Async, Stream};
+use std::{
+    collections::VecDeque,
+    time::{Instant, SystemTime},
+};
 
 use crate::Error;
 
@@ -20,24 +25,97 @@ where
     }
 }
 
-impl Multipart<hyper::Body> for hyper::Response<hyper::Body> {
+/// Body stream used when turning an already buffered body into multipart.
+pub type Buffered = futures::stream::Once<Bytes, Error>;
+
+impl<S, E, B> Multipart<S> for http::Response<S>
+where
+    B: AsRef<[u8]>,
+    S: Stream<Item = B, Error = E>,
+    E: std::fmt::Display + Send + 'static,
+{
+    fn into_multipart_with_capacity(self, capacity: usize) -> Result<MultipartChunks<S>, Error> {
+        let (parts, body) = self.into_parts();
+        MultipartChunks::from_parts_with_capacity(body, &parts.headers, capacity)
+    }
+}
+
+impl<S, E, B> Multipart<S> for http::Request<S>
+where
+    B: AsRef<[u8]>,
+    S: Stream<Item = B, Error = E>,
+    E: std::fmt::Display + Send + 'static,
+{
+    fn into_multipart_with_capacity(self, capacity: usize) -> Result<MultipartChunks<S>, Error> {
+        let (parts, body) = self.into_parts();
+
+        MultipartChunks::from_parts_with_capacity(body, &parts.headers, capacity)
+    }
+}
+
+impl Multipart<Buffered> for http::Response<Bytes> {
     fn into_multipart_with_capacity(
         self,
         capacity: usize,
-    ) -> Result<MultipartChunks<hyper::Body>, Error> {
+    ) -> Result<MultipartChunks<Buffered>, Error> {
         let (parts, body) = self.into_parts();
-        MultipartChunks::from_parts_with_capacity(body, &parts.headers, capacity)
+        (parts.headers, body).into_multipart_with_capacity(capacity)
+    }
+}
+
+impl Multipart<Buffered> for http::Response<Vec<u8>> {
+    fn into_multipart_with_capacity(
+        self,
+        capacity: usize,
+    ) -> Result<MultipartChunks<Buffered>, Error> {
+        self.map(Bytes::from).into_multipart_with_capacity(capacity)
     }
 }
 
-impl Multipart<hyper::Body> for hyper::Request<hyper::Body> {
+impl Multipart<Buffered> for http::Request<Bytes> {
     fn into_multipart_with_capacity(
         self,
         capacity: usize,
-    ) -> Result<MultipartChunks<hyper::Body>, Error> {
+    ) -> Result<MultipartChunks<Buffered>, Error> {
         let (parts, body) = self.into_parts();
+        (parts.headers, body).into_multipart_with_capacity(capacity)
+    }
+}
 
-        MultipartChunks::from_parts_with_capacity(body, &parts.headers, capacity)
+impl Multipart<Buffered> for http::Request<Vec<u8>> {
+    fn into_multipart_with_capacity(
+        self,
+        capacity: usize,
+    ) -> Result<MultipartChunks<Buffered>, Error> {
+        self.map(Bytes::from).into_multipart_with_capacity(capacity)
+    }
+}
+
+impl<H> Multipart<Buffered> for (H, Bytes)
+where
+    H: crate::HeaderMap,
+{
+    fn into_multipart_with_capacity(
+        self,
+        capacity: usize,
+    ) -> Result<MultipartChunks<Buffered>, Error> {
+        let (headers, body) = self;
+        let body_stream = futures::stream::once(Ok(body));
+
+        MultipartChunks::from_parts_with_capacity(body_stream, &headers, capacity)
+    }
+}
+
+impl<H> Multipart<Buffered> for (H, Vec<u8>)
+where
+    H: crate::HeaderMap,
+{
+    fn into_multipart_with_capacity(
+        self,
+        capacity: usize,
+    ) -> Result<MultipartChunks<Buffered>, Error> {
+        let (headers, body) = self;
+        (headers, Bytes::from(body)).into_multipart_with_capacity(capacity)
     }
 }
 
@@ -56,11 +134,33 @@ where
     }
 }
 
+/// What `MultipartChunks` does when a part declares a `Content-Length` that
+/// differs from the length of its body.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LengthCheck {
+    /// Do not look at `Content-Length`.
+    #[default]
+    Ignore,
+    /// Log the mismatch, count it in `Stats::length_mismatches` and yield the part.
+    Warn,
+    /// Fail the stream with `Error::LengthMismatch`.
+    Reject,
+}
+
 pub struct MultipartChunks<S> {
     inner: S,
     parser: Parser,
     inner_done: bool,
     inner_error: Option<Error>,
+    stats: Stats,
+    header_syntax: HeaderSyntax,
+    digest: bool,
+    /// The body is multipart/form-data, which may carry a `_charset_` field.
+    form_data: bool,
+    default_charset: Charset,
+    length_check: LengthCheck,
+    /// Offset just past each received chunk not yet fully parsed, with its arrival time.
+    arrivals: VecDeque<(u64, Instant, SystemTime)>,
 }
 
 impl<S, E, B> MultipartChunks<S>
@@ -75,13 +175,71 @@ where
         capacity: usize,
     ) -> Result<Self, Error> {
         let parser = Parser::from_with_capacity(headers, capacity)?;
+
+        let subtype = headers
+            .get_value(http::header::CONTENT_TYPE)
+            .and_then(|ct| ct.parse::<mime::Mime>().ok())
+            .map(|ct| ct.subtype().as_str().to_ascii_lowercase());
+
+        // Parts of a multipart/digest default to message/rfc822.
+        let digest = subtype.as_deref() == Some("digest");
+        let form_data = subtype.as_deref() == Some("form-data");
+
         Ok(Self {
             inner: stream,
             inner_done: false,
             inner_error: None,
             parser,
+            stats: Stats::default(),
+            header_syntax: HeaderSyntax::default(),
+            digest,
+            form_data,
+            default_charset: Charset::default(),
+            length_check: LengthCheck::default(),
+            arrivals: VecDeque::new(),
         })
     }
+
+    /// Sets the syntax the produced parts use to parse their headers, for
+    /// streams separating header names and values with something else than ':'.
+    pub fn with_header_syntax(mut self, syntax: HeaderSyntax) -> Self {
+        self.header_syntax = syntax;
+        self
+    }
+
+    /// Sets how parts whose body does not match their declared `Content-Length`
+    /// are handled, by default they are not checked.
+    pub fn with_length_check(mut self, check: LengthCheck) -> Self {
+        self.length_check = check;
+        self
+    }
+
+    /// Returns the bytes buffered but not consumed by the parser, together with
+    /// the inner stream. After the stream has ended these are the bytes that
+    /// followed the closing delimiter, which is useful when the multipart body
+    /// is followed by more data on the same stream.
+    pub fn into_inner(self) -> (Bytes, S) {
+        (self.parser.into_buffer(), self.inner)
+    }
+
+    /// Arrival time of the chunk holding the byte before `end`. Chunks ending
+    /// before it are forgotten, later parts cannot end in them.
+    fn arrived(&mut self, end: u64) -> (Instant, SystemTime) {
+        while self.arrivals.len() > 1 && self.arrivals[0].0 < end {
+            self.arrivals.pop_front();
+        }
+
+        match self.arrivals.front() {
+            Some(&(_, arrived, arrived_at)) => (arrived, arrived_at),
+            None => (Instant::now(), SystemTime::now()),
+        }
+    }
+
+    /// Returns a handle to the progress counters of this stream. The handle is
+    /// cheap to clone and can be read from other tasks while the stream is polled.
+    pub fn stats(&self) -> Stats {
+        self.stats.clone()
+    }
 }
 
 impl<S, I, E> Stream for MultipartChunks<S>
@@ -99,21 +257,72 @@ where
         match self.inner.poll() {
             Ok(Async::Ready(None)) => {
                 self.inner_done = true;
+                self.parser.end_of_input();
             }
             Err(e) => {
                 self.inner_done = true;
                 self.inner_error = Some(Error::inner(e));
             }
 
-            Ok(Async::Ready(Some(chunk))) => self.parser.add_bytes(chunk),
+            Ok(Async::Ready(Some(chunk))) => {
+                self.stats.add_received(chunk.as_ref().len());
+                self.arrivals.push_back((
+                    self.stats.bytes_received(),
+                    Instant::now(),
+                    SystemTime::now(),
+                ));
+                self.parser.add_bytes(chunk)
+            }
 
             Ok(Async::NotReady) => inner_not_ready = true,
         }
 
-        match self.parser.parse() {
+        let result = self.parser.parse();
+        self.stats
+            .set_buffer(self.parser.buffered_len(), self.parser.pending_part_len());
+
+        match result {
             ParseResult::Done => Ok(Async::Ready(None)),
-            ParseResult::Err(err) => Err(err.into()),
-            ParseResult::Ready(bytes) => Ok(Async::Ready(Some(Part::from(bytes)))),
+            ParseResult::Err(err) => Err(err),
+            ParseResult::Ready(bytes) => {
+                let index = self.stats.parts() as usize;
+                self.stats.add_part();
+
+                let range = self.parser.last_part_range();
+                let (arrived, arrived_at) = self.arrived(range.end);
+                let part = Part::from(bytes)
+                    .with_origin(Origin {
+                        index,
+                        range,
+                        arrived,
+                        arrived_at,
+                    })
+                    .with_header_syntax(self.header_syntax)
+                    .with_digest(self.digest)
+                    .with_default_charset(self.default_charset);
+
+                // A `_charset_` form field sets the charset of the fields
+                // following it that do not declare one, RFC 7578 section 4.6.
+                if self.form_data && part.name().as_deref() == Some("_charset_") {
+                    match std::str::from_utf8(part.body()).map(Charset::from_label) {
+                        Ok(Ok(charset)) => self.default_charset = charset,
+                        _ => log::warn!("Ignoring unsupported _charset_ {:?}", part.body()),
+                    }
+                }
+
+                if self.length_check != LengthCheck::Ignore {
+                    if let Err(e) = check_length(index, &part) {
+                        if self.length_check == LengthCheck::Reject {
+                            return Err(e);
+                        }
+
+                        log::warn!("{}", e);
+                        self.stats.add_length_mismatch();
+                    }
+                }
+
+                Ok(Async::Ready(Some(part)))
+            }
 
             ParseResult::NotReady if self.inner_done => match self.inner_error.take() {
                 Some(err) => Err(err),
@@ -130,3 +339,262 @@ where
         }
     }
 }
+
+/// Compares the declared `Content-Length` of a part, if any, with its body.
+fn check_length(index: usize, part: &Part) -> Result<(), Error> {
+    let headers = part.headers();
+    let declared = match headers.get(http::header::CONTENT_LENGTH) {
+        None => return Ok(()),
+        Some(value) => value,
+    };
+
+    let expected = declared
+        .to_str()
+        .ok()
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .ok_or_else(|| Error::InvalidHeader(format!("Content-Length: {:?}", declared)))?;
+    let actual = part.body_len() as u64;
+
+    if expected == actual {
+        Ok(())
+    } else {
+        Err(Error::LengthMismatch {
+            part: index,
+            expected,
+            actual,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    const BODY: &str = "--simple boundary\r
+Content-Type: text/plain\r
+\r
+Part1\r
+--simple boundary\r
+Content-Type: text/plain\r
+\r
+Part2\r
+--simple boundary--\r
+";
+
+    fn response<B>(body: B) -> http::Response<B> {
+        http::Response::builder()
+            .header(
+                "Content-Type",
+                "multipart/mixed; boundary=\"simple boundary\"",
+            )
+            .body(body)
+            .expect("Building response")
+    }
+
+    fn bodies<S: Stream<Item = Part, Error = Error>>(s: S) -> Vec<Vec<u8>> {
+        s.wait()
+            .map(|part| part.expect("Parsing part").body().to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn multipart_from_buffered_bodies() {
+        let exp = vec![b"Part1".to_vec(), b"Part2".to_vec()];
+
+        let from_bytes = response(Bytes::from(BODY)).into_multipart().unwrap();
+        assert_eq!(exp, bodies(from_bytes));
+
+        let from_vec = response(BODY.as_bytes().to_vec()).into_multipart().unwrap();
+        assert_eq!(exp, bodies(from_vec));
+    }
+
+    #[test]
+    fn multipart_from_custom_body_stream() {
+        let chunks: Vec<Result<&[u8], String>> = BODY.as_bytes().chunks(7).map(Ok).collect();
+
+        let stream = response(futures::stream::iter_result(chunks))
+            .into_multipart()
+            .unwrap();
+
+        assert_eq!(vec![b"Part1".to_vec(), b"Part2".to_vec()], bodies(stream));
+    }
+
+    #[test]
+    fn stats_track_stream_progress() {
+        let stream = response(Bytes::from(BODY)).into_multipart().unwrap();
+        let stats = stream.stats();
+
+        assert_eq!(0, stats.bytes_received());
+
+        let mut parts = stream.wait();
+        parts.next().unwrap().unwrap();
+
+        assert_eq!(BODY.len() as u64, stats.bytes_received());
+        assert_eq!(1, stats.parts());
+        assert!(stats.buffered() > 0);
+
+        assert!(parts.next().unwrap().is_ok());
+        assert!(parts.next().is_none());
+        assert_eq!(2, stats.parts());
+    }
+
+    #[test]
+    fn into_inner_returns_trailing_bytes() {
+        let chunks: Vec<Result<&[u8], String>> =
+            vec![Ok(BODY.as_bytes()), Ok(b"next response"), Ok(b" continues")];
+
+        let mut stream = response(futures::stream::iter_result(chunks))
+            .into_multipart()
+            .unwrap();
+
+        let parts: Vec<Part> = stream.by_ref().wait().map(|p| p.unwrap()).collect();
+        assert_eq!(2, parts.len());
+
+        let (rest, inner) = stream.into_inner();
+        let rest: Vec<u8> = inner.wait().fold(rest.to_vec(), |mut acc, chunk| {
+            acc.extend_from_slice(chunk.unwrap());
+            acc
+        });
+
+        assert_eq!(&b"next response continues"[..], &rest[..]);
+    }
+
+    #[test]
+    fn into_inner_after_split_closing_delimiter() {
+        let chunks: Vec<Result<&[u8], String>> = vec![
+            Ok(b"--simple boundary\r\n\r\nP1\r\n--simple boundary"),
+            Ok(b"--"),
+            Ok(b"\r\nNEXT"),
+        ];
+
+        let mut stream = response(futures::stream::iter_result(chunks))
+            .into_multipart()
+            .unwrap();
+
+        assert_eq!(vec![b"P1".to_vec()], bodies(stream.by_ref()));
+        assert_eq!(&b"NEXT"[..], &stream.into_inner().0[..]);
+    }
+
+    #[test]
+    fn digest_parts_default_to_rfc822() {
+        let body = "--b\r\n\r\nFrom: a@example.com\r\n--b--\r\n";
+        let mut headers = http::HeaderMap::new();
+        headers.insert(
+            http::header::CONTENT_TYPE,
+            "multipart/digest; boundary=b".parse().unwrap(),
+        );
+
+        let part = (headers, body.as_bytes().to_vec())
+            .into_multipart()
+            .unwrap()
+            .wait()
+            .next()
+            .unwrap()
+            .unwrap();
+
+        assert_eq!("message/rfc822", part.content_type().unwrap().as_ref());
+    }
+
+    #[test]
+    fn form_charset_field_sets_default_charset() {
+        let body = &b"--b\r\n\
+Content-Disposition: form-data; name=\"_charset_\"\r\n\r\n\
+iso-8859-1\r\n\
+--b\r\n\
+Content-Disposition: form-data; name=\"field\"\r\n\r\n\
+r\xe4ka\r\n\
+--b--\r\n"[..];
+        let mut headers = http::HeaderMap::new();
+        headers.insert(
+            http::header::CONTENT_TYPE,
+            "multipart/form-data; boundary=b".parse().unwrap(),
+        );
+
+        let parts: Vec<Part> = (headers, body.to_vec())
+            .into_multipart()
+            .unwrap()
+            .wait()
+            .map(|p| p.unwrap())
+            .collect();
+
+        assert_eq!("r\u{e4}ka", parts[1].text().unwrap());
+
+        // Only form-data bodies carry the charset in a field.
+        let mut headers = http::HeaderMap::new();
+        headers.insert(
+            http::header::CONTENT_TYPE,
+            "multipart/mixed; boundary=b".parse().unwrap(),
+        );
+
+        let parts: Vec<Part> = (headers, body.to_vec())
+            .into_multipart()
+            .unwrap()
+            .wait()
+            .map(|p| p.unwrap())
+            .collect();
+
+        assert!(parts[1].text().is_err());
+    }
+
+    #[test]
+    fn validate_content_length() {
+        let body = "--simple boundary\r
+Content-Length: 5\r
+\r
+Part1\r
+--simple boundary\r
+Content-Length: 9\r
+\r
+Part2\r
+--simple boundary--\r
+";
+        let parts = |check| {
+            response(Bytes::from(body))
+                .into_multipart()
+                .unwrap()
+                .with_length_check(check)
+        };
+
+        assert_eq!(2, parts(LengthCheck::Ignore).wait().count());
+
+        let warned = parts(LengthCheck::Warn);
+        let stats = warned.stats();
+        assert_eq!(2, warned.wait().filter(|p| p.is_ok()).count());
+        assert_eq!(1, stats.length_mismatches());
+
+        let mut rejected = parts(LengthCheck::Reject).wait();
+        assert!(rejected.next().unwrap().is_ok());
+        match rejected.next().unwrap() {
+            Err(Error::LengthMismatch {
+                part: 1,
+                expected: 9,
+                actual: 5,
+            }) => {}
+            other => panic!("Expected LengthMismatch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parts_record_their_origin() {
+        let chunks: Vec<Result<&[u8], String>> = BODY.as_bytes().chunks(30).map(Ok).collect();
+        let parts: Vec<Part> = response(futures::stream::iter_result(chunks))
+            .into_multipart()
+            .unwrap()
+            .wait()
+            .map(|p| p.unwrap())
+            .collect();
+
+        for (i, part) in parts.iter().enumerate() {
+            let origin = part.origin().unwrap();
+            assert_eq!(i, origin.index);
+
+            let raw = &BODY.as_bytes()[origin.range.start as usize..origin.range.end as usize];
+            assert!(raw.ends_with(part.body()));
+            assert!(raw.starts_with(b"Content-Type"));
+        }
+
+        let (first, second) = (parts[0].origin().unwrap(), parts[1].origin().unwrap());
+        assert!(first.arrived <= second.arrived);
+    }
+}
@@ -1,5 +1,6 @@
 use crate::Error;
 use bytes::{Bytes, BytesMut};
+use std::ops::Range;
 
 #[derive(Debug)]
 pub enum ParseResult {
@@ -31,27 +32,11 @@ impl Parser {
         headers: &H,
         capacity: usize,
     ) -> Result<Self, Error> {
-        let content_type = headers
-            .get_value(http::header::CONTENT_TYPE)
-            .ok_or(Error::ContentTypeMissing)?;
+        let boundary = boundary_from_headers(headers)?;
 
-        let mime_type = content_type
-            .parse::<mime::Mime>()
-            .map_err(Error::InvalidMimeType)?;
-
-        if mime_type.type_() != mime::MULTIPART {
-            return Err(Error::NotMultipart);
-        }
-
-        match mime_type.get_param("boundary") {
-            Some(boundary) => {
-                log::debug!("Creating Boundary Parser");
-                let bp = BoundaryParser::with_capacity(boundary, capacity);
-                Ok(Parser::Boundary(bp))
-            }
-
-            None => return Err(Error::malformed("mime param boundary missing")),
-        }
+        log::debug!("Creating Boundary Parser");
+        let bp = BoundaryParser::with_capacity(boundary, capacity);
+        Ok(Parser::Boundary(bp))
     }
 
     pub fn add_bytes<T>(&mut self, bs: T)
@@ -68,12 +53,76 @@ impl Parser {
             Parser::Boundary(ref mut inner) => inner.parse(),
         }
     }
+
+    /// Tells the parser no more bytes will be added.
+    pub fn end_of_input(&mut self) {
+        match self {
+            Parser::Boundary(ref mut inner) => inner.end_of_input(),
+        }
+    }
+
+    /// Number of bytes currently held in the buffer.
+    pub fn buffered_len(&self) -> usize {
+        match self {
+            Parser::Boundary(ref inner) => inner.buffered_len(),
+        }
+    }
+
+    /// Number of buffered bytes belonging to the part not yet completed.
+    pub fn pending_part_len(&self) -> usize {
+        match self {
+            Parser::Boundary(ref inner) => inner.pending_part_len(),
+        }
+    }
+
+    /// Returns the bytes not consumed by the parser.
+    pub fn into_buffer(self) -> Bytes {
+        match self {
+            Parser::Boundary(inner) => inner.into_buffer(),
+        }
+    }
+
+    /// Offsets in the input of the bytes of the last part returned by `parse`.
+    pub fn last_part_range(&self) -> Range<u64> {
+        match self {
+            Parser::Boundary(ref inner) => inner.last_part_range(),
+        }
+    }
+}
+
+/// Reads the boundary from the multipart Content-Type header.
+pub(crate) fn boundary_from_headers<H: crate::HeaderMap>(headers: &H) -> Result<String, Error> {
+    let content_type = headers
+        .get_value(http::header::CONTENT_TYPE)
+        .ok_or(Error::ContentTypeMissing)?;
+
+    let mime_type = content_type
+        .parse::<mime::Mime>()
+        .map_err(Error::InvalidMimeType)?;
+
+    if mime_type.type_() != mime::MULTIPART {
+        return Err(Error::NotMultipart);
+    }
+
+    match mime_type.get_param("boundary") {
+        Some(boundary) => Ok(boundary.as_str().to_string()),
+        None => Err(Error::malformed("mime param boundary missing")),
+    }
 }
 
 #[derive(Debug)]
 pub struct BoundaryParser {
     boundary: String,
     buffer: BytesMut,
+    done: bool,
+    /// No more bytes will be added.
+    end_of_input: bool,
+    /// Number of bytes consumed from the start of the input.
+    consumed: u64,
+    last_part: Range<u64>,
+    /// Offset in `buffer` of the first byte of the part in progress, once its
+    /// boundary line has been seen.
+    part_start: Option<usize>,
 }
 
 impl BoundaryParser {
@@ -85,6 +134,11 @@ impl BoundaryParser {
         Self {
             boundary,
             buffer: BytesMut::with_capacity(capacity),
+            done: false,
+            end_of_input: false,
+            consumed: 0,
+            last_part: 0..0,
+            part_start: None,
         }
     }
 
@@ -92,7 +146,63 @@ impl BoundaryParser {
         self.buffer.extend(bs.as_ref())
     }
 
+    /// Tells the parser no more bytes will be added, so a closing delimiter
+    /// at the very end of the input is not waited on for its CRLF.
+    pub fn end_of_input(&mut self) {
+        self.end_of_input = true;
+    }
+
+    pub fn buffered_len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Returns the bytes not consumed by the parser. Once `parse` has returned
+    /// `Done` these are the bytes following the closing delimiter line.
+    pub fn into_buffer(self) -> Bytes {
+        self.buffer.freeze()
+    }
+
+    /// Bytes buffered for the part in progress as of the last call to
+    /// `parse`, not counting a delimiter cut off at the end of the buffer.
+    /// Zero before the first part and once the closing delimiter has been
+    /// seen.
+    pub fn pending_part_len(&self) -> usize {
+        let start = match self.part_start {
+            Some(start) if !self.done => start,
+            _ => return 0,
+        };
+
+        // A delimiter cut off at the end of the buffer is not part of the body.
+        let body = &self.buffer[start..];
+        let delimiter_len = self.boundary.len() + 2;
+        let tail = (1..=body.len().min(delimiter_len))
+            .rev()
+            .find(|&n| {
+                let suffix = &body[body.len() - n..];
+                suffix.starts_with(&b"\r\n"[..n.min(2)])
+                    && (n <= 2 || self.boundary.as_bytes().starts_with(&suffix[2..]))
+            })
+            .unwrap_or(0);
+
+        body.len() - tail
+    }
+
+    /// Offsets in the input of the bytes of the last part returned by `parse`,
+    /// from the end of its leading boundary line to the CRLF before the next.
+    pub fn last_part_range(&self) -> Range<u64> {
+        self.last_part.clone()
+    }
+
+    fn advance(&mut self, n: usize) {
+        self.buffer.advance(n);
+        self.consumed += n as u64;
+    }
+
     pub fn parse(&mut self) -> ParseResult {
+        if self.done {
+            return ParseResult::Done;
+        }
+
         // Read the starting boundary.
         let boundary = self.boundary.as_bytes();
 
@@ -126,6 +236,18 @@ impl BoundaryParser {
             BOUNDARY_LAST_PART_SENTINEL => {
                 log::debug!("Found stop sentinel at index: {}", part_start);
 
+                // Consume the closing delimiter line, keeping whatever follows
+                // it. Wait for the CRLF ending the line unless the input ended.
+                let rest = &self.buffer[part_start + 2..];
+                if !self.end_of_input && rest.len() < 2 && CRLF.starts_with(rest) {
+                    return ParseResult::NotReady;
+                }
+
+                let line_end = if rest.starts_with(CRLF) { 4 } else { 2 };
+                self.advance(part_start + line_end);
+
+                self.done = true;
+                self.part_start = None;
                 return ParseResult::Done;
             }
 
@@ -145,13 +267,27 @@ impl BoundaryParser {
             Some(i) => {
                 // We've found an entire part, snap it of and return it.
 
-                self.buffer.advance(part_start);
+                self.advance(part_start);
                 let part_bs = self.buffer.split_to(i - 2).freeze();
-                self.buffer.advance(2); // advance past the leading crlf in the next part.
+                self.last_part = self.consumed..self.consumed + part_bs.len() as u64;
+                self.consumed += part_bs.len() as u64;
+                self.advance(2); // advance past the leading crlf in the next part.
+
+                // The buffer now starts with the next boundary, its part
+                // begins after the line's CRLF.
+                let boundary_len = self.boundary.len();
+                let line_end = boundary_len + 2;
+                self.part_start = match self.buffer.get(boundary_len..line_end) {
+                    Some(CRLF) => Some(line_end),
+                    _ => None,
+                };
                 ParseResult::Ready(part_bs)
             }
 
-            None => ParseResult::NotReady,
+            None => {
+                self.part_start = Some(part_start);
+                ParseResult::NotReady
+            }
         }
     }
 }
@@ -222,6 +358,21 @@ Part2\r
         assert_eq!(ParseResult::Done, p.parse());
     }
 
+    #[test]
+    fn track_part_offsets() {
+        let data = "preamble\r\n--b\r\n\r\nPart1\r\n--b\r\nX: y\r\n\r\nPart2\r\n--b--\r\n";
+
+        let mut p = BoundaryParser::with_capacity("b", 500);
+        p.add_bytes(data.as_bytes());
+
+        for exp in &["\r\nPart1", "X: y\r\n\r\nPart2"] {
+            assert_eq!(ParseResult::Ready((*exp).into()), p.parse());
+
+            let range = p.last_part_range();
+            assert_eq!(exp, &&data[range.start as usize..range.end as usize]);
+        }
+    }
+
     #[test]
     fn parse_boundary_with_preamble() {
         let data = "\r
@@ -261,4 +412,82 @@ Part2\r
         assert_eq!(ParseResult::Done, p.parse());
     }
 
+    #[test]
+    fn keep_bytes_after_closing_delimiter() {
+        let data = "--simple boundary\r
+Content-type: text/plain\r
+\r
+Part1\r
+--simple boundary--\r
+HTTP/1.1 200 OK\r
+";
+
+        let mut p = BoundaryParser::with_capacity("simple boundary", 500);
+        p.add_bytes(data.as_bytes());
+
+        assert_eq!(
+            ParseResult::Ready("Content-type: text/plain\r\n\r\nPart1".into()),
+            p.parse()
+        );
+        assert_eq!(ParseResult::Done, p.parse());
+        assert_eq!(ParseResult::Done, p.parse());
+
+        assert_eq!(&b"HTTP/1.1 200 OK\r\n"[..], &p.into_buffer()[..]);
+    }
+
+    #[test]
+    fn closing_delimiter_split_across_chunks() {
+        let mut p = BoundaryParser::with_capacity("b", 500);
+
+        p.add_bytes(b"--b\r\n\r\nP1\r\n--b");
+        assert_eq!(ParseResult::Ready("\r\nP1".into()), p.parse());
+        assert_eq!(ParseResult::NotReady, p.parse());
+
+        p.add_bytes(b"--");
+        assert_eq!(ParseResult::NotReady, p.parse());
+
+        p.add_bytes(b"\r\nNEXT");
+        assert_eq!(ParseResult::Done, p.parse());
+        assert_eq!(&b"NEXT"[..], &p.into_buffer()[..]);
+
+        // Without a CRLF at the end of the input.
+        let mut p = BoundaryParser::with_capacity("b", 500);
+        p.add_bytes(b"--b\r\n\r\nP1\r\n--b--");
+        assert_eq!(ParseResult::Ready("\r\nP1".into()), p.parse());
+        assert_eq!(ParseResult::NotReady, p.parse());
+
+        p.end_of_input();
+        assert_eq!(ParseResult::Done, p.parse());
+        assert!(p.into_buffer().is_empty());
+    }
+
+    #[test]
+    fn pending_part_len_counts_from_last_delimiter() {
+        let mut p = BoundaryParser::with_capacity("b", 500);
+        assert_eq!(0, p.pending_part_len());
+
+        p.add_bytes(b"preamble\r\n--b\r\n\r\nP1\r\n--b\r\n\r\nP2");
+        assert!(matches!(p.parse(), ParseResult::Ready(_)));
+        assert_eq!(4, p.pending_part_len());
+        assert!(matches!(p.parse(), ParseResult::NotReady));
+        assert_eq!(4, p.pending_part_len());
+
+        // A cut off delimiter is not counted as part of the body.
+        p.add_bytes(b"\r\n-");
+        assert!(matches!(p.parse(), ParseResult::NotReady));
+        assert_eq!(4, p.pending_part_len());
+
+        // Bytes looking like the start of a delimiter only at first are.
+        p.add_bytes(b"x");
+        assert!(matches!(p.parse(), ParseResult::NotReady));
+        assert_eq!(8, p.pending_part_len());
+
+        p.add_bytes(b"\r\n--b");
+        assert!(matches!(p.parse(), ParseResult::Ready(_)));
+        assert_eq!(0, p.pending_part_len());
+
+        p.add_bytes(b"--\r\n");
+        assert!(matches!(p.parse(), ParseResult::Done));
+        assert_eq!(0, p.pending_part_len());
+    }
 }
@@ -1,13 +1,178 @@
-use bytes::Bytes;
+use crate::{
+    encoder::encode_headers_with, Charset, ContentDisposition, Error, FrameMeta, TransferEncoding,
+};
+use bytes::{BufMut, Bytes, BytesMut};
 use http::header::{HeaderMap, HeaderName, HeaderValue};
+use std::{
+    io,
+    ops::Range,
+    sync::OnceLock,
+    time::{Instant, SystemTime},
+};
 
+/// Separator between header names and values.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HeaderSyntax {
+    /// `Name: value`, as in http.
+    #[default]
+    Colon,
+    /// `Name=value`, used by many MJPEG cameras.
+    Equals,
+    /// Whichever of `:` and `=` comes first on each line.
+    Auto,
+}
+
+impl HeaderSyntax {
+    fn separator(self, line: &[u8]) -> Option<usize> {
+        match self {
+            HeaderSyntax::Colon => line.iter().position(|b| *b == b':'),
+            HeaderSyntax::Equals => line.iter().position(|b| *b == b'='),
+            HeaderSyntax::Auto => line.iter().position(|b| *b == b':' || *b == b'='),
+        }
+    }
+}
+
+/// Where and when a part was received, recorded by `MultipartChunks`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Origin {
+    /// Position of the part in the stream, starting at 0.
+    pub index: usize,
+    /// Byte offsets of the part in the multipart body, from the end of its
+    /// boundary line up to the line break before the next delimiter.
+    pub range: Range<u64>,
+    /// When the last byte of the part arrived.
+    pub arrived: Instant,
+    /// `arrived` as wall-clock time.
+    pub arrived_at: SystemTime,
+}
+
+#[derive(Clone, Debug)]
 pub struct Part {
     // Just store the headers as the entire lines for now.
     headers_data: Bytes,
     pub body_data: Bytes,
+    /// The blank line ending the header section was present, it is missing in
+    /// parts that are nothing but a body.
+    separator: bool,
+    header_syntax: HeaderSyntax,
+    digest: bool,
+    default_charset: Charset,
+    origin: Option<Origin>,
+    /// `headers_data` parsed with `header_syntax`, on first use.
+    parsed: OnceLock<HeaderMap<HeaderValue>>,
 }
 
 impl Part {
+    pub(crate) fn from_raw(headers_data: Bytes, body_data: Bytes) -> Self {
+        Part {
+            headers_data,
+            body_data,
+            separator: true,
+            header_syntax: HeaderSyntax::default(),
+            digest: false,
+            default_charset: Charset::default(),
+            origin: None,
+            parsed: OnceLock::new(),
+        }
+    }
+
+    /// Sets the syntax used by `headers` and `try_headers` to parse the header lines.
+    pub fn with_header_syntax(mut self, syntax: HeaderSyntax) -> Self {
+        if syntax != self.header_syntax {
+            self.header_syntax = syntax;
+            self.parsed = OnceLock::new();
+        }
+        self
+    }
+
+    /// Marks the part as an entity of a `multipart/digest` body, which changes
+    /// the default content type to `message/rfc822`.
+    pub fn with_digest(mut self, digest: bool) -> Self {
+        self.digest = digest;
+        self
+    }
+
+    /// Sets the charset `text` uses when the Content-Type has none, e.g. from
+    /// the `_charset_` field of a form (RFC 7578).
+    pub fn with_default_charset(mut self, charset: Charset) -> Self {
+        self.default_charset = charset;
+        self
+    }
+
+    pub(crate) fn with_origin(mut self, origin: Origin) -> Self {
+        self.origin = Some(origin);
+        self
+    }
+
+    /// The position and arrival time of the part, for parts produced by
+    /// `MultipartChunks`.
+    pub fn origin(&self) -> Option<&Origin> {
+        self.origin.as_ref()
+    }
+
+    /// The header section as received, without the blank line ending it.
+    pub fn header_data(&self) -> &Bytes {
+        &self.headers_data
+    }
+
+    /// The part in wire format: the header section as received, the blank line
+    /// ending it and the body, i.e. the bytes between two delimiters.
+    /// `Part::from` parses it back into the same header section and body. The
+    /// blank line is left out for parts that were received without it.
+    pub fn to_bytes(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(self.headers_data.len() + self.body_data.len() + 4);
+
+        if !self.headers_data.is_empty() {
+            buf.put_slice(&self.headers_data);
+            buf.put_slice(b"\r\n");
+        }
+        if self.has_separator() {
+            buf.put_slice(b"\r\n");
+        }
+        buf.put_slice(&self.body_data);
+
+        buf.freeze()
+    }
+
+    /// Writes the part in wire format, see `to_bytes`.
+    pub fn write_to<W: io::Write>(&self, w: &mut W) -> io::Result<()> {
+        if !self.headers_data.is_empty() {
+            w.write_all(&self.headers_data)?;
+            w.write_all(b"\r\n")?;
+        }
+        if self.has_separator() {
+            w.write_all(b"\r\n")?;
+        }
+        w.write_all(&self.body_data)
+    }
+
+    /// Headers added by `rewrite_headers` always need the blank line.
+    fn has_separator(&self) -> bool {
+        self.separator || !self.headers_data.is_empty()
+    }
+
+    /// Passes the headers to `f` for rewriting. The header section is only
+    /// re-encoded if `f` changed the headers, otherwise the bytes as received
+    /// are kept. Re-encoding drops malformed lines, as `headers` does.
+    pub fn rewrite_headers<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(&mut HeaderMap<HeaderValue>),
+    {
+        let mut headers = self.headers();
+        f(&mut headers);
+
+        if headers != *self.parsed_headers() {
+            let separator: &[u8] = match self.header_syntax {
+                HeaderSyntax::Equals => b"=",
+                HeaderSyntax::Colon | HeaderSyntax::Auto => b": ",
+            };
+            self.headers_data = Bytes::from(encode_headers_with(&headers, separator));
+            self.parsed = OnceLock::new();
+        }
+
+        self
+    }
+
     pub fn body(&self) -> &[u8] {
         &self.body_data
     }
@@ -21,8 +186,6 @@ impl Part {
     }
 
     /// Returns an iterator over all the headers lines, with their line endings trimmed.
-    /// Since many jpeg streams uses Headers separated by '=' instead of Https ':' this
-    /// is currently the only way to get the headers.
     pub fn header_lines(&self) -> impl Iterator<Item = Result<&str, std::str::Utf8Error>> {
         let slice = &self.headers_data;
         slice.split(|e| *e == b'\n').map(|line| {
@@ -31,56 +194,249 @@ impl Part {
         })
     }
 
+    /// Parses the headers. Folded lines are unfolded and repeated headers are
+    /// all kept. Malformed lines are logged and skipped, use `try_headers` to
+    /// have them reported as errors.
+    ///
+    /// Many jpeg streams separate headers with '=' instead of ':', see
+    /// `with_header_syntax`.
     pub fn headers(&self) -> HeaderMap<HeaderValue> {
-        let mut res = HeaderMap::new();
+        self.parsed_headers().clone()
+    }
 
-        self.header_lines()
-            .filter_map(|line| line.ok())
-            .filter_map(|s| parse_header_line(s))
-            .for_each(|(name, value)| {
-                res.insert(name, value);
-            });
+    /// The headers are parsed once, the accessors below all read them from here.
+    fn parsed_headers(&self) -> &HeaderMap<HeaderValue> {
+        self.parsed
+            .get_or_init(|| parse_headers(&self.headers_data, self.header_syntax))
+    }
 
-        res
+    /// Like `headers`, but fails on the first malformed header line.
+    pub fn try_headers(&self) -> Result<HeaderMap<HeaderValue>, Error> {
+        try_parse_headers(&self.headers_data, self.header_syntax)
+    }
+
+    /// Parses the `Content-Type` header. Without one the RFC 2046 default is
+    /// returned, `text/plain; charset=us-ascii`, or `message/rfc822` for parts
+    /// of a `multipart/digest` body.
+    ///
+    /// Note that `charset` does not follow this default, see there.
+    pub fn content_type(&self) -> Result<mime::Mime, Error> {
+        match self.parsed_headers().get(http::header::CONTENT_TYPE) {
+            Some(value) => value
+                .to_str()
+                .map_err(|_| Error::InvalidHeader(format!("Content-Type: {:?}", value)))?
+                .parse()
+                .map_err(Error::InvalidMimeType),
+            None if self.digest => Ok("message/rfc822".parse().expect("Valid mime")),
+            None => Ok("text/plain; charset=us-ascii".parse().expect("Valid mime")),
+        }
+    }
+
+    /// The `Content-Transfer-Encoding` of the body, 7bit if the header is missing.
+    pub fn transfer_encoding(&self) -> Result<TransferEncoding, Error> {
+        TransferEncoding::from_headers(self.parsed_headers())
+    }
+
+    /// The body with its `Content-Transfer-Encoding` undone. Fails on unknown
+    /// encodings and malformed base64.
+    pub fn decoded_body(&self) -> Result<Bytes, Error> {
+        self.transfer_encoding()?.decode(&self.body_data)
+    }
+
+    /// The `charset` parameter of the Content-Type, otherwise the default
+    /// charset, UTF-8 unless set with `with_default_charset`.
+    ///
+    /// Without a Content-Type this differs from the us-ascii of `content_type`
+    /// on purpose: UTF-8 is a superset of us-ascii, so conforming bodies decode
+    /// the same, while form fields, which browsers send without a Content-Type,
+    /// decode correctly.
+    pub fn charset(&self) -> Result<Charset, Error> {
+        if !self
+            .parsed_headers()
+            .contains_key(http::header::CONTENT_TYPE)
+        {
+            return Ok(self.default_charset);
+        }
+
+        match self.content_type()?.get_param(mime::CHARSET) {
+            Some(label) => Charset::from_label(label.as_str()),
+            None => Ok(self.default_charset),
+        }
+    }
+
+    /// The body decoded to text by its transfer encoding and charset. Fails on
+    /// bytes invalid in the charset, see `text_lossy`.
+    pub fn text(&self) -> Result<String, Error> {
+        self.charset()?.decode(&self.decoded_body()?)
+    }
+
+    /// Like `text`, but replaces invalid bytes with U+FFFD. Still fails on an
+    /// unsupported charset or transfer encoding.
+    pub fn text_lossy(&self) -> Result<String, Error> {
+        Ok(self.charset()?.decode_lossy(&self.decoded_body()?))
+    }
+
+    /// Deserializes a JSON part, decoding the body by its transfer encoding and
+    /// charset first. Fails with `Error::Deserialize`, identifying the part by
+    /// its index and its name or Content-ID, if the Content-Type is not JSON or
+    /// the body cannot be deserialized into `T`.
+    #[cfg(feature = "json")]
+    pub fn json<T: serde::de::DeserializeOwned>(&self) -> Result<T, Error> {
+        let failed = |message: String| Error::Deserialize {
+            index: self.origin().map(|o| o.index),
+            part: self.name().or_else(|| {
+                self.parsed_headers()
+                    .get("content-id")
+                    .and_then(|id| id.to_str().ok())
+                    .map(str::to_string)
+            }),
+            message,
+        };
+
+        let content_type = self.content_type().map_err(|e| failed(e.to_string()))?;
+        if content_type.subtype() != mime::JSON && content_type.suffix() != Some(mime::JSON) {
+            return Err(failed(format!("expected JSON, found {}", content_type)));
+        }
+
+        let text = self.text().map_err(|e| failed(e.to_string()))?;
+        serde_json::from_str(&text).map_err(|e| failed(e.to_string()))
+    }
+
+    /// Typed access to the timestamp and other metadata headers of camera frames.
+    pub fn frame_meta(&self) -> FrameMeta {
+        FrameMeta::new(self.headers())
+    }
+
+    /// Parses the `Content-Disposition` header, `None` if the part has none.
+    pub fn content_disposition(&self) -> Result<Option<ContentDisposition>, Error> {
+        match self.parsed_headers().get(http::header::CONTENT_DISPOSITION) {
+            None => Ok(None),
+            Some(value) => ContentDisposition::parse(value.as_bytes()).map(Some),
+        }
+    }
+
+    /// The form field name from the `Content-Disposition` header. A missing or
+    /// malformed header gives `None`, use `content_disposition` to tell them apart.
+    pub fn name(&self) -> Option<String> {
+        self.disposition_lenient()
+            .and_then(|cd| cd.name().map(String::from))
+    }
+
+    /// The file name from the `Content-Disposition` header, preferring the
+    /// decoded `filename*` parameter over `filename`.
+    pub fn filename(&self) -> Option<String> {
+        self.disposition_lenient()
+            .and_then(|cd| cd.filename().map(String::from))
+    }
+
+    fn disposition_lenient(&self) -> Option<ContentDisposition> {
+        self.content_disposition().unwrap_or_else(|e| {
+            log::warn!("Ignoring Content-Disposition: {}", e);
+            None
+        })
     }
 }
 
-fn parse_header_line(s: &str) -> Option<(HeaderName, HeaderValue)> {
-    if let None = s.find(":") {
-        return None;
+/// Parses a raw header section into a `HeaderMap`, skipping malformed lines.
+pub(crate) fn parse_headers(data: &[u8], syntax: HeaderSyntax) -> HeaderMap<HeaderValue> {
+    let mut res = HeaderMap::new();
+
+    for field in header_fields(data, syntax) {
+        match field {
+            Ok((name, value)) => {
+                res.append(name, value);
+            }
+            Err(e) => log::warn!("Skipping header: {}", e),
+        }
     }
 
-    let mut parts = s.split(":");
+    res
+}
 
-    let header_name = parts
-        .next()
-        .map(|s| HeaderName::from_bytes(s.trim().as_bytes()));
+/// Parses a raw header section into a `HeaderMap`.
+pub(crate) fn try_parse_headers(
+    data: &[u8],
+    syntax: HeaderSyntax,
+) -> Result<HeaderMap<HeaderValue>, Error> {
+    let mut res = HeaderMap::new();
 
-    let header_value = parts.next().map(|s| HeaderValue::from_str(s.trim()));
+    for field in header_fields(data, syntax) {
+        let (name, value) = field?;
+        res.append(name, value);
+    }
 
-    match (header_name, header_value) {
-        (Some(Ok(name)), Some(Ok(value))) => Some((name, value)),
-        _ => None,
+    Ok(res)
+}
+
+/// Splits a header section into its fields, unfolding continuation lines.
+fn header_fields(
+    data: &[u8],
+    syntax: HeaderSyntax,
+) -> impl Iterator<Item = Result<(HeaderName, HeaderValue), Error>> {
+    let mut fields: Vec<Vec<u8>> = Vec::new();
+
+    for line in data.split(|b| *b == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+
+        match (line.first(), fields.last_mut()) {
+            (None, _) => {}
+            // Continuation of the previous line.
+            (Some(b' '), Some(field)) | (Some(b'\t'), Some(field)) => field.extend_from_slice(line),
+            _ => fields.push(line.to_vec()),
+        }
     }
+
+    fields
+        .into_iter()
+        .map(move |field| parse_header_line(&field, syntax))
+}
+
+fn parse_header_line(
+    line: &[u8],
+    syntax: HeaderSyntax,
+) -> Result<(HeaderName, HeaderValue), Error> {
+    let invalid = || Error::InvalidHeader(String::from_utf8_lossy(line).into_owned());
+
+    let sep = syntax.separator(line).ok_or_else(invalid)?;
+    let (name, value) = (&line[..sep], &line[sep + 1..]);
+
+    let name = HeaderName::from_bytes(trim(name)).map_err(|_| invalid())?;
+    let value = HeaderValue::from_bytes(trim(value)).map_err(|_| invalid())?;
+
+    Ok((name, value))
+}
+
+fn trim(bs: &[u8]) -> &[u8] {
+    let is_space = |b: &u8| *b == b' ' || *b == b'\t';
+    let start = bs.iter().position(|b| !is_space(b)).unwrap_or(bs.len());
+    let end = bs
+        .iter()
+        .rposition(|b| !is_space(b))
+        .map_or(start, |i| i + 1);
+
+    &bs[start..end]
 }
 
 impl From<Bytes> for Part {
     fn from(mut bs: Bytes) -> Self {
         // split headers and body
 
+        // A part without headers starts with the empty line ending the header section.
+        if bs.starts_with(b"\r\n") {
+            bs.advance(2);
+            return Part::from_raw(Bytes::with_capacity(0), bs);
+        }
+
         match twoway::find_bytes(&bs[..], b"\r\n\r\n") {
-            // No headers
+            // No headers, and no blank line either.
             None => Part {
-                headers_data: Bytes::with_capacity(0),
-                body_data: bs,
+                separator: false,
+                ..Part::from_raw(Bytes::with_capacity(0), bs)
             },
             Some(p) => {
                 let headers = bs.split_to(p);
                 bs.advance(4); // remove the leading CRLF for body.
-                Part {
-                    headers_data: headers,
-                    body_data: bs,
-                }
+                Part::from_raw(headers, bs)
             }
         }
     }
@@ -88,23 +444,7 @@ impl From<Bytes> for Part {
 
 impl From<&[u8]> for Part {
     fn from(bs: &[u8]) -> Self {
-        // split headers and body
-
-        match twoway::find_bytes(&bs[..], b"\r\n\r\n") {
-            // No headers
-            None => Part {
-                headers_data: Bytes::with_capacity(0),
-                body_data: bs.to_vec().into(),
-            },
-            Some(i) => {
-                let header_end = i + 4;
-
-                Part {
-                    headers_data: bs[0..i].to_vec().into(),
-                    body_data: bs[header_end..bs.len()].to_vec().into(),
-                }
-            }
-        }
+        Part::from(Bytes::from(bs.to_vec()))
     }
 }
 
@@ -132,7 +472,8 @@ mod tests {
         ];
 
         for (header, exp_name, exp_val) in &tests {
-            let (name, val) = parse_header_line(header).expect("Parse header line");
+            let (name, val) = parse_header_line(header.as_bytes(), HeaderSyntax::Colon)
+                .expect("Parse header line");
 
             assert_eq!(exp_name, &name.as_str());
             assert_eq!(
@@ -142,4 +483,249 @@ mod tests {
         }
     }
 
+    #[test]
+    fn part_without_headers() {
+        // The blank line ending an empty header section is not part of the
+        // body. Up to 0.4.1 it was kept, `\r\nbody` had the body `\r\nbody`,
+        // and a blank line later in the body split it into bogus headers.
+        let part = Part::from(&b"\r\nbody"[..]);
+        assert!(part.headers().is_empty());
+        assert_eq!(&b"body"[..], part.body());
+
+        let part = Part::from(&b"\r\nbody\r\n\r\nwith empty line"[..]);
+
+        assert!(part.headers().is_empty());
+        assert_eq!(&b"body\r\n\r\nwith empty line"[..], part.body());
+    }
+
+    #[test]
+    fn colons_in_header_values() {
+        let (name, val) =
+            parse_header_line(b"X-Url: http://host:80/x", HeaderSyntax::Colon).unwrap();
+
+        assert_eq!("x-url", name.as_str());
+        assert_eq!("http://host:80/x", val);
+    }
+
+    #[test]
+    fn unfold_and_keep_duplicate_headers() {
+        let part = Part::from(
+            &b"X-Folded: first\r\n  second\r\n\tthird\r\nX-Dup: 1\r\nX-Dup: 2\r\n\r\nbody"[..],
+        );
+        let headers = part.try_headers().unwrap();
+
+        assert_eq!("first  second\tthird", headers["x-folded"]);
+
+        let dups: Vec<_> = headers.get_all("x-dup").iter().collect();
+        assert_eq!(vec!["1", "2"], dups);
+    }
+
+    #[test]
+    fn report_malformed_header_lines() {
+        let part = Part::from(&b"Content-Type: text/plain\r\nno separator\r\n\r\nbody"[..]);
+
+        match part.try_headers() {
+            Err(Error::InvalidHeader(line)) => assert_eq!("no separator", line),
+            other => panic!("Expected InvalidHeader, got {:?}", other),
+        }
+
+        // The lenient variant skips the line.
+        let headers = part.headers();
+        assert_eq!(1, headers.len());
+        assert_eq!("text/plain", headers["content-type"]);
+    }
+
+    #[test]
+    fn equals_separated_headers() {
+        let data = &b"Content-Type=image/jpeg\r\nX-Url=http://host:80/x?a=b\r\n\r\nbody"[..];
+
+        let headers = Part::from(data)
+            .with_header_syntax(HeaderSyntax::Equals)
+            .try_headers()
+            .unwrap();
+        assert_eq!("image/jpeg", headers["content-type"]);
+        assert_eq!("http://host:80/x?a=b", headers["x-url"]);
+
+        // The default syntax cannot make sense of them.
+        assert!(Part::from(data).try_headers().is_err());
+    }
+
+    #[test]
+    fn auto_header_syntax() {
+        let part = Part::from(&b"Content-Type: a=b\r\nX-Timestamp=1.5\r\n\r\nbody"[..])
+            .with_header_syntax(HeaderSyntax::Auto);
+        let headers = part.try_headers().unwrap();
+
+        assert_eq!("a=b", headers["content-type"]);
+        assert_eq!("1.5", headers["x-timestamp"]);
+    }
+
+    #[test]
+    fn content_type_defaults() {
+        let part = Part::from(&b"Content-Type: image/jpeg\r\n\r\nbody"[..]);
+        assert_eq!(mime::IMAGE_JPEG, part.content_type().unwrap());
+
+        let part = Part::from(&b"\r\nbody"[..]);
+        assert_eq!(
+            "text/plain; charset=us-ascii",
+            part.content_type().unwrap().as_ref()
+        );
+
+        let part = part.with_digest(true);
+        assert_eq!("message/rfc822", part.content_type().unwrap().as_ref());
+
+        let part = Part::from(&b"Content-Type: not a mime\r\n\r\nbody"[..]);
+        assert!(part.content_type().is_err());
+    }
+
+    #[test]
+    fn decode_transfer_encoding() {
+        let part =
+            Part::from(&b"Content-Transfer-Encoding: base64\r\n\r\naGVsbG8g\r\nd29ybGQ=\r\n"[..]);
+        assert_eq!(&b"hello world"[..], &part.decoded_body().unwrap()[..]);
+
+        let part = Part::from(&b"\r\nplain"[..]);
+        assert_eq!(&b"plain"[..], &part.decoded_body().unwrap()[..]);
+    }
+
+    #[test]
+    fn text_in_charset() {
+        let part = Part::from(&b"Content-Type: text/plain; charset=ISO-8859-15\r\n\r\n\xa4 5"[..]);
+        assert_eq!("\u{20ac} 5", part.text().unwrap());
+
+        let part = Part::from(&b"\r\nr\xe4ka"[..]);
+        assert!(part.text().is_err());
+        assert_eq!("r\u{fffd}ka", part.text_lossy().unwrap());
+
+        let part = part.with_default_charset(Charset::Windows(1252));
+        assert_eq!("r\u{e4}ka", part.text().unwrap());
+
+        let part = Part::from(&b"Content-Type: text/plain; charset=koi8-r\r\n\r\nx"[..]);
+        assert!(part.text_lossy().is_err());
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn deserialize_json_parts() {
+        use std::collections::HashMap;
+
+        let part = Part::from(
+            &b"Content-Disposition: form-data; name=\"meta\"\r\n\
+Content-Type: application/vnd.api+json; charset=iso-8859-1\r\n\r\n\
+{\"r\xe4ka\": 1}"[..],
+        );
+        let meta: HashMap<String, u32> = part.json().unwrap();
+        assert_eq!(Some(&1), meta.get("r\u{e4}ka"));
+
+        match part.json::<Vec<u32>>() {
+            Err(Error::Deserialize { index, part, .. }) => {
+                assert_eq!(None, index);
+                assert_eq!(Some("meta".to_string()), part);
+            }
+            other => panic!("Expected Deserialize, got {:?}", other),
+        }
+
+        let part = Part::from(&b"Content-Type: text/plain\r\n\r\n{}"[..]);
+        assert!(part.json::<HashMap<String, u32>>().is_err());
+
+        // Parts of a stream are identified by index and Content-ID.
+        let part = Part::from(&b"Content-Type: application/json\r\nContent-ID: <cfg>\r\n\r\n["[..])
+            .with_origin(Origin {
+                index: 3,
+                range: 0..0,
+                arrived: Instant::now(),
+                arrived_at: SystemTime::now(),
+            });
+        let err = part.json::<Vec<u32>>().unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("Deserializing part 3 \"<cfg>\" failed"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn round_trip_wire_format() {
+        let inputs: &[&[u8]] = &[
+            b"Content-Type: text/plain\r\nX-Folded: a\r\n b\r\n\r\nbody\r\n\r\nmore",
+            b"\r\nno headers",
+            b"\r\n",
+            b"just body",
+            b"",
+            b"Content-Type=image/jpeg\r\n\r\n",
+        ];
+
+        for input in inputs {
+            let part = Part::from(*input);
+            assert_eq!(input, &&part.to_bytes()[..]);
+
+            let mut written = Vec::new();
+            part.write_to(&mut written).unwrap();
+            assert_eq!(input, &&written[..]);
+
+            let again = Part::from(part.to_bytes());
+            assert_eq!(part.header_data(), again.header_data());
+            assert_eq!(part.body(), again.body());
+        }
+    }
+
+    #[test]
+    fn rewrite_part_headers() {
+        let input = &b"content-type:text/plain\r\nX-Secret: 1\r\n\r\nbody"[..];
+
+        // Unchanged headers keep their original bytes.
+        let part = Part::from(input).rewrite_headers(|_| {});
+        assert_eq!(input, &part.to_bytes()[..]);
+
+        let part = Part::from(input).rewrite_headers(|headers| {
+            headers.remove("x-secret");
+        });
+        assert_eq!(
+            &b"Content-Type: text/plain\r\n\r\nbody"[..],
+            &part.to_bytes()[..]
+        );
+
+        let part = Part::from(&b"X-A=1\r\n\r\nbody"[..])
+            .with_header_syntax(HeaderSyntax::Equals)
+            .rewrite_headers(|headers| {
+                headers.insert("x-b", HeaderValue::from_static("2"));
+            });
+        assert_eq!(&b"X-A=1\r\nX-B=2\r\n\r\nbody"[..], &part.to_bytes()[..]);
+
+        // Headers added to a bare body get the blank line ending them.
+        let part = Part::from(&b"just body"[..]).rewrite_headers(|headers| {
+            headers.insert("x-b", HeaderValue::from_static("2"));
+        });
+        assert_eq!(&b"X-B: 2\r\n\r\njust body"[..], &part.to_bytes()[..]);
+    }
+
+    #[test]
+    fn disposition_accessors() {
+        let part = Part::from(
+            &b"Content-Disposition: form-data; name=\"upload\"; filename*=UTF-8''%C3%A4.txt\r\n\r\nbody"[..],
+        );
+
+        assert_eq!(Some("upload".to_string()), part.name());
+        assert_eq!(Some("\u{e4}.txt".to_string()), part.filename());
+
+        let part = Part::from(&b"\r\nbody"[..]);
+        assert!(part.content_disposition().unwrap().is_none());
+        assert_eq!(None, part.filename());
+    }
+
+    #[test]
+    fn cached_headers_follow_changes() {
+        let part = Part::from(&b"Content-Type=image/jpeg\r\n\r\njpeg"[..]);
+        assert!(part.headers().is_empty());
+
+        let part = part.with_header_syntax(HeaderSyntax::Equals);
+        assert_eq!(mime::IMAGE_JPEG, part.content_type().unwrap());
+
+        let part = part.rewrite_headers(|headers| {
+            headers.insert("content-type", HeaderValue::from_static("image/png"));
+        });
+        assert_eq!(mime::IMAGE_PNG, part.content_type().unwrap());
+        assert_eq!(&b"Content-Type=image/png"[..], &part.header_data()[..]);
+    }
 }
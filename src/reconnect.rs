@@ -0,0 +1,529 @@
+use crate::{Error, Multipart, MultipartChunks, Part};
+use futures::{Async, Future, Poll, Stream};
+use http::{StatusCode, Uri};
+use hyper::client::{connect::Connect, HttpConnector, ResponseFuture};
+use std::time::{Duration, Instant};
+use tokio::timer::Delay;
+
+/// Exponential backoff used between reconnection attempts.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    /// Delay before the first reconnection attempt.
+    pub initial: Duration,
+    /// Upper bound of the delay between attempts.
+    pub max: Duration,
+    /// The delay is multiplied with this factor for every failed attempt.
+    pub factor: u32,
+    /// Give up after this many consecutive failed attempts. `None` retries forever.
+    pub max_retries: Option<u32>,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(30),
+            factor: 2,
+            max_retries: None,
+        }
+    }
+}
+
+impl Backoff {
+    /// Delay before the given attempt, attempts are counted from 1.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let mut delay = self.initial;
+
+        for _ in 1..attempt {
+            delay = match delay.checked_mul(self.factor) {
+                Some(d) if d < self.max => d,
+                _ => return self.max,
+            };
+        }
+
+        delay.min(self.max)
+    }
+}
+
+/// Connection events reported by `Reconnect`.
+#[derive(Debug)]
+pub enum ReconnectEvent {
+    /// A multipart response was received from the server.
+    Connected,
+    /// The connection, or the multipart stream on it, failed, ended or
+    /// stalled for longer than the idle timeout.
+    Disconnected(Error),
+    /// The server answered with a non 2xx status, or with a body that is
+    /// not multipart.
+    Rejected { status: StatusCode, error: Error },
+    /// A new connection will be made after `delay`.
+    Reconnecting { attempt: u32, delay: Duration },
+    /// `Backoff::max_retries` consecutive attempts failed, the stream ends
+    /// with the error of the last one.
+    GaveUp { attempts: u32 },
+}
+
+enum State {
+    /// The first request is made when the stream is first polled.
+    Start,
+    Connecting(ResponseFuture),
+    Streaming(Box<MultipartChunks<hyper::Body>>),
+    Waiting(Delay),
+}
+
+type EventHandler = Box<dyn FnMut(&ReconnectEvent) + Send>;
+
+/// A long lived stream of parts from a multipart http resource, typically an
+/// MJPEG camera. When the connection drops or the multipart stream reports an
+/// error, a new request is made after a backoff delay. The stream only fails
+/// once `Backoff::max_retries` consecutive attempts have failed.
+///
+/// A connection that does not deliver any bytes for the idle timeout, if one
+/// is set, counts as dropped too.
+///
+/// Must be polled within a tokio runtime, since the backoff uses its timer.
+pub struct Reconnect<C> {
+    client: hyper::Client<C, hyper::Body>,
+    uri: Uri,
+    backoff: Backoff,
+    on_event: Option<EventHandler>,
+    attempt: u32,
+    state: State,
+    idle_timeout: Option<Duration>,
+    /// Fires when the current connection has been idle for `idle_timeout`.
+    stall: Option<Delay>,
+    /// Bytes received on the current connection when `stall` was last reset.
+    received: u64,
+}
+
+impl Reconnect<HttpConnector> {
+    /// Connects to `uri` using a default hyper client.
+    pub fn get(uri: Uri) -> Self {
+        Self::new(hyper::Client::new(), uri)
+    }
+}
+
+impl<C> Reconnect<C>
+where
+    C: Connect + Sync + 'static,
+{
+    pub fn new(client: hyper::Client<C, hyper::Body>, uri: Uri) -> Self {
+        Self {
+            client,
+            uri,
+            backoff: Backoff::default(),
+            on_event: None,
+            attempt: 0,
+            state: State::Start,
+            idle_timeout: None,
+            stall: None,
+            received: 0,
+        }
+    }
+
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Reconnects when no response, or no bytes of the response body, arrive
+    /// for `timeout`.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Calls `f` for every connection event.
+    pub fn on_event<F>(mut self, f: F) -> Self
+    where
+        F: FnMut(&ReconnectEvent) + Send + 'static,
+    {
+        self.on_event = Some(Box::new(f));
+        self
+    }
+
+    fn emit(&mut self, event: ReconnectEvent) {
+        log::debug!("Reconnect event: {:?}", event);
+
+        if let Some(ref mut f) = self.on_event {
+            f(&event);
+        }
+    }
+
+    fn connect(&mut self) {
+        self.state = State::Connecting(self.client.get(self.uri.clone()));
+        self.reset_stall(0);
+    }
+
+    fn reset_stall(&mut self, received: u64) {
+        self.received = received;
+        self.stall = self
+            .idle_timeout
+            .map(|timeout| Delay::new(Instant::now() + timeout));
+    }
+
+    /// Whether the idle timeout passed without more than `received` bytes
+    /// arriving on the current connection.
+    fn stalled(&mut self, received: u64) -> Result<bool, Error> {
+        if received != self.received {
+            self.reset_stall(received);
+        }
+
+        match self.stall {
+            Some(ref mut delay) => match delay.poll() {
+                Ok(Async::Ready(())) => Ok(true),
+                Ok(Async::NotReady) => Ok(false),
+                Err(e) => Err(Error::inner(e)),
+            },
+            None => Ok(false),
+        }
+    }
+
+    fn timed_out(&mut self) -> Result<(), Error> {
+        let timeout = self.idle_timeout.unwrap_or_default();
+        self.disconnected(Error::IdleTimeout(timeout), None)
+    }
+
+    /// Schedules the next attempt, or returns the error if we are out of
+    /// retries. `status` is set when the server rejected the request.
+    fn disconnected(&mut self, err: Error, status: Option<StatusCode>) -> Result<(), Error> {
+        self.attempt += 1;
+        self.stall = None;
+
+        if let Some(max) = self.backoff.max_retries {
+            if self.attempt > max {
+                self.emit(ReconnectEvent::GaveUp { attempts: max });
+                return Err(err);
+            }
+        }
+
+        self.emit(match status {
+            Some(status) => ReconnectEvent::Rejected { status, error: err },
+            None => ReconnectEvent::Disconnected(err),
+        });
+
+        let delay = self.backoff.delay(self.attempt);
+        self.emit(ReconnectEvent::Reconnecting {
+            attempt: self.attempt,
+            delay,
+        });
+
+        self.state = State::Waiting(Delay::new(Instant::now() + delay));
+        Ok(())
+    }
+}
+
+impl<C> Stream for Reconnect<C>
+where
+    C: Connect + Sync + 'static,
+{
+    type Item = Part;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            let next = match self.state {
+                State::Start => {
+                    self.connect();
+                    continue;
+                }
+
+                State::Connecting(ref mut f) => match f.poll() {
+                    Ok(Async::NotReady) => {
+                        if self.stalled(0)? {
+                            self.timed_out()?;
+                            continue;
+                        }
+                        return Ok(Async::NotReady);
+                    }
+                    Ok(Async::Ready(response)) => {
+                        let status = response.status();
+                        if status.is_success() {
+                            response.into_multipart().map_err(|e| (e, Some(status)))
+                        } else {
+                            Err((Error::UnexpectedStatus(status), Some(status)))
+                        }
+                    }
+                    Err(e) => Err((Error::from(e), None)),
+                },
+
+                State::Streaming(ref mut s) => match s.poll() {
+                    Ok(Async::Ready(Some(part))) => {
+                        self.attempt = 0;
+                        return Ok(Async::Ready(Some(part)));
+                    }
+                    Ok(Async::Ready(None)) => {
+                        self.disconnected(Error::malformed("Multipart stream ended"), None)?;
+                        continue;
+                    }
+                    Ok(Async::NotReady) => {
+                        let received = s.stats().bytes_received();
+                        if self.stalled(received)? {
+                            self.timed_out()?;
+                            continue;
+                        }
+                        return Ok(Async::NotReady);
+                    }
+                    Err(e) => {
+                        self.disconnected(e, None)?;
+                        continue;
+                    }
+                },
+
+                State::Waiting(ref mut delay) => match delay.poll() {
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Ok(Async::Ready(())) => {
+                        self.connect();
+                        continue;
+                    }
+                    Err(e) => return Err(Error::inner(e)),
+                },
+            };
+
+            match next {
+                Ok(chunks) => {
+                    self.state = State::Streaming(Box::new(chunks));
+                    self.reset_stall(0);
+                    self.emit(ReconnectEvent::Connected);
+                }
+                Err((e, status)) => self.disconnected(e, status)?,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use hyper::{service::service_fn_ok, Body, Response, Server};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn backoff_delay() {
+        let backoff = Backoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_millis(1000),
+            factor: 3,
+            max_retries: None,
+        };
+
+        assert_eq!(Duration::from_millis(100), backoff.delay(1));
+        assert_eq!(Duration::from_millis(300), backoff.delay(2));
+        assert_eq!(Duration::from_millis(900), backoff.delay(3));
+        assert_eq!(Duration::from_millis(1000), backoff.delay(4));
+        assert_eq!(Duration::from_millis(1000), backoff.delay(100));
+    }
+
+    fn event_name(event: &ReconnectEvent) -> &'static str {
+        match event {
+            ReconnectEvent::Connected => "connected",
+            ReconnectEvent::Disconnected(_) => "disconnected",
+            ReconnectEvent::Rejected { .. } => "rejected",
+            ReconnectEvent::Reconnecting { .. } => "reconnecting",
+            ReconnectEvent::GaveUp { .. } => "gave up",
+        }
+    }
+
+    /// Serves two parts per connection and then aborts the response body.
+    fn dropping_server() -> (Uri, impl Future<Item = (), Error = ()>) {
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(|| {
+            service_fn_ok(|_req| {
+                let parts = futures::stream::iter_ok(vec![
+                    "--frame\r\nContent-Type: image/jpeg\r\n\r\nframe1\r\n",
+                    "--frame\r\nContent-Type: image/jpeg\r\n\r\nframe2\r\n",
+                    "--frame\r\n",
+                ]);
+
+                // Give hyper time to flush the parts before aborting.
+                let dropped = Delay::new(Instant::now() + Duration::from_millis(20))
+                    .then(|_| -> Result<&'static str, std::io::Error> {
+                        Err(std::io::Error::other("dropped"))
+                    })
+                    .into_stream();
+
+                Response::builder()
+                    .header("Content-Type", "multipart/x-mixed-replace; boundary=frame")
+                    .body(Body::wrap_stream(parts.chain(dropped)))
+                    .unwrap()
+            })
+        });
+
+        let uri = format!("http://{}/", server.local_addr()).parse().unwrap();
+        (uri, server.map_err(|e| panic!("Server failed: {}", e)))
+    }
+
+    #[test]
+    fn reconnects_when_connection_drops() {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let (uri, server) = dropping_server();
+        rt.spawn(server);
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = events.clone();
+
+        let backoff = Backoff {
+            initial: Duration::from_millis(1),
+            ..Backoff::default()
+        };
+
+        let parts = Reconnect::get(uri)
+            .with_backoff(backoff)
+            .on_event(move |e| recorded.lock().unwrap().push(event_name(e)))
+            .take(5)
+            .map(|part| part.into_body())
+            .collect();
+
+        let bodies = rt.block_on(parts).expect("Reading parts");
+        assert_eq!(
+            vec!["frame1", "frame2", "frame1", "frame2", "frame1"],
+            bodies
+                .iter()
+                .map(|b| std::str::from_utf8(b).unwrap())
+                .collect::<Vec<_>>()
+        );
+
+        let events = events.lock().unwrap();
+        assert_eq!(
+            &[
+                "connected",
+                "disconnected",
+                "reconnecting",
+                "connected",
+                "disconnected",
+                "reconnecting",
+                "connected"
+            ],
+            &events[..]
+        );
+    }
+
+    #[test]
+    fn gives_up_after_max_retries() {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+
+        // Nothing is listening on a freshly closed port.
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let uri = format!("http://{}/", addr).parse().unwrap();
+
+        let backoff = Backoff {
+            initial: Duration::from_millis(1),
+            max_retries: Some(2),
+            ..Backoff::default()
+        };
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = events.clone();
+
+        let res = rt.block_on(
+            Reconnect::get(uri)
+                .with_backoff(backoff)
+                .on_event(move |e| recorded.lock().unwrap().push(event_name(e)))
+                .collect(),
+        );
+
+        assert!(res.is_err());
+        assert_eq!(
+            &[
+                "disconnected",
+                "reconnecting",
+                "disconnected",
+                "reconnecting",
+                "gave up"
+            ],
+            &events.lock().unwrap()[..]
+        );
+    }
+
+    #[test]
+    fn reports_rejected_responses() {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(|| {
+            service_fn_ok(|_req| {
+                Response::builder()
+                    .status(503)
+                    .body(Body::from("busy"))
+                    .unwrap()
+            })
+        });
+        let uri = format!("http://{}/", server.local_addr()).parse().unwrap();
+        rt.spawn(server.map_err(|e| panic!("Server failed: {}", e)));
+
+        let backoff = Backoff {
+            initial: Duration::from_millis(1),
+            max_retries: Some(1),
+            ..Backoff::default()
+        };
+
+        let statuses = Arc::new(Mutex::new(Vec::new()));
+        let recorded = statuses.clone();
+
+        let res = rt.block_on(
+            Reconnect::get(uri)
+                .with_backoff(backoff)
+                .on_event(move |e| {
+                    if let ReconnectEvent::Rejected { status, .. } = e {
+                        recorded.lock().unwrap().push(*status);
+                    }
+                })
+                .collect(),
+        );
+
+        match res {
+            Err(Error::UnexpectedStatus(status)) => assert_eq!(503, status.as_u16()),
+            other => panic!("Expected an unexpected status error, got {:?}", other),
+        }
+        assert_eq!(
+            vec![StatusCode::SERVICE_UNAVAILABLE],
+            *statuses.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn reconnects_when_connection_stalls() {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+
+        // Serves a single part per connection and then sends nothing.
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(|| {
+            service_fn_ok(|_req| {
+                let part = futures::stream::once::<_, std::io::Error>(Ok(
+                    "--frame\r\n\r\nframe1\r\n--frame\r\n",
+                ));
+                let stalled = futures::future::empty().into_stream();
+
+                Response::builder()
+                    .header("Content-Type", "multipart/x-mixed-replace; boundary=frame")
+                    .body(Body::wrap_stream(part.chain(stalled)))
+                    .unwrap()
+            })
+        });
+        let uri = format!("http://{}/", server.local_addr()).parse().unwrap();
+        rt.spawn(server.map_err(|e| panic!("Server failed: {}", e)));
+
+        let backoff = Backoff {
+            initial: Duration::from_millis(1),
+            ..Backoff::default()
+        };
+
+        let timeouts = Arc::new(Mutex::new(0));
+        let counted = timeouts.clone();
+
+        let parts = Reconnect::get(uri)
+            .with_backoff(backoff)
+            .with_idle_timeout(Duration::from_millis(50))
+            .on_event(move |e| {
+                if let ReconnectEvent::Disconnected(Error::IdleTimeout(_)) = e {
+                    *counted.lock().unwrap() += 1;
+                }
+            })
+            .take(2)
+            .collect();
+
+        let parts = rt.block_on(parts).expect("Reading parts");
+        assert_eq!(2, parts.len());
+        assert_eq!(1, *timeouts.lock().unwrap());
+    }
+}
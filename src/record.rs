@@ -0,0 +1,607 @@
+//! Recording of part streams to disk.
+//!
+//! A recording is a directory holding segment files and an index. Segment
+//! files are named `{n:08}.seg` and start with the magic `HMPR` followed by a
+//! version byte. After that follows one record per part:
+//!
+//! ```text
+//! u64  arrival time, microseconds since the unix epoch
+//! u32  length of the header section
+//! u32  length of the body
+//! ...  header section, as received
+//! ...  body
+//! ```
+//!
+//! All integers are big endian. The `index` file holds one fixed size entry
+//! per record: arrival time (u64), segment number (u32), offset of the record
+//! within the segment (u64) and the length of the record (u32). A record,
+//! headers and body included, is therefore limited to 4 GiB.
+//!
+//! Index entries can reach the disk before the segment bytes they point to.
+//! Entries from the first one whose record is not fully within its segment
+//! are ignored when a recording is opened.
+//!
+//! Writing is done with blocking file IO. `Recorder::record` runs it through
+//! `tokio_threadpool::blocking` so the reactor is not stalled.
+
+use crate::{Error, Part};
+use bytes::Bytes;
+use futures::{future, Async, Future, Stream};
+use std::{
+    convert::TryFrom,
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Default max size of a segment file before a new one is started.
+pub const DEFAULT_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+
+const MAGIC: &[u8] = b"HMPR";
+const VERSION: u8 = 1;
+const FILE_HEADER_LEN: u64 = 5;
+const RECORD_HEADER_LEN: usize = 16;
+const INDEX_ENTRY_LEN: usize = 24;
+const INDEX_FILE: &str = "index";
+
+/// Location of a single recorded part.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub arrived: SystemTime,
+    pub segment: u32,
+    pub offset: u64,
+    pub len: u32,
+}
+
+/// A part read back from a recording, together with its arrival time.
+#[derive(Debug, Clone)]
+pub struct RecordedPart {
+    pub arrived: SystemTime,
+    pub part: Part,
+}
+
+/// Appends parts to a recording directory.
+pub struct Recorder {
+    dir: PathBuf,
+    segment_size: u64,
+    segment: u32,
+    segment_len: u64,
+    writer: BufWriter<File>,
+    index: BufWriter<File>,
+    last_arrival: u64,
+    /// A write failed part way, the files may end in a partial record.
+    failed: bool,
+}
+
+impl Recorder {
+    /// Creates a recording in `dir`, creating the directory if needed. When
+    /// `dir` already holds a recording, new parts are appended to it in a new
+    /// segment.
+    pub fn create<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let existing = read_index(&dir)?;
+        let (segment, last_arrival) = match existing.last() {
+            Some(entry) => (entry.segment + 1, micros(entry.arrived)),
+            None => (0, 0),
+        };
+
+        let index = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(INDEX_FILE))?;
+
+        // Discard a partially written trailing entry and entries pointing
+        // past the end of their segment.
+        index.set_len((existing.len() * INDEX_ENTRY_LEN) as u64)?;
+
+        let writer = create_segment(&dir, segment)?;
+
+        Ok(Self {
+            dir,
+            segment_size: DEFAULT_SEGMENT_SIZE,
+            segment,
+            segment_len: FILE_HEADER_LEN,
+            writer,
+            index: BufWriter::new(index),
+            last_arrival,
+            failed: false,
+        })
+    }
+
+    /// Starts a new segment when the current one grows beyond `bytes`.
+    pub fn with_segment_size(mut self, bytes: u64) -> Self {
+        self.segment_size = bytes;
+        self
+    }
+
+    /// Appends the part, using its arrival time if known and otherwise the
+    /// current time.
+    pub fn write(&mut self, part: &Part) -> io::Result<IndexEntry> {
+        let arrived = part.origin().map_or_else(SystemTime::now, |o| o.arrived_at);
+        self.write_at(part, arrived)
+    }
+
+    /// Appends the part with the given arrival time. Arrival times in the index
+    /// never decrease, an arrival before the previous one is recorded as the
+    /// previous arrival time.
+    ///
+    /// Fails with `InvalidInput` when the record would be larger than 4 GiB.
+    /// Once writing to the files failed every further write fails as well;
+    /// create a new recorder for the directory to continue the recording in
+    /// a new segment.
+    pub fn write_at(&mut self, part: &Part, arrived: SystemTime) -> io::Result<IndexEntry> {
+        self.check_failed()?;
+
+        let headers = part.header_data();
+        let body = part.body();
+        let len = RECORD_HEADER_LEN + headers.len() + body.len();
+        // The record length bounds the header and body lengths as well.
+        let len = u32::try_from(len).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Part too large to record, the limit is 4 GiB",
+            )
+        })?;
+
+        let res = self.append(headers, body, len, arrived);
+        self.failed = res.is_err();
+        res
+    }
+
+    fn append(
+        &mut self,
+        headers: &[u8],
+        body: &[u8],
+        len: u32,
+        arrived: SystemTime,
+    ) -> io::Result<IndexEntry> {
+        if self.segment_len > FILE_HEADER_LEN
+            && self.segment_len + u64::from(len) > self.segment_size
+        {
+            self.writer.flush()?;
+            self.segment += 1;
+            self.segment_len = FILE_HEADER_LEN;
+            self.writer = create_segment(&self.dir, self.segment)?;
+        }
+
+        let arrived = micros(arrived).max(self.last_arrival);
+        self.last_arrival = arrived;
+
+        let mut record_header = [0u8; RECORD_HEADER_LEN];
+        record_header[0..8].copy_from_slice(&arrived.to_be_bytes());
+        record_header[8..12].copy_from_slice(&(headers.len() as u32).to_be_bytes());
+        record_header[12..16].copy_from_slice(&(body.len() as u32).to_be_bytes());
+
+        self.writer.write_all(&record_header)?;
+        self.writer.write_all(headers)?;
+        self.writer.write_all(body)?;
+
+        let entry = IndexEntry {
+            arrived: from_micros(arrived),
+            segment: self.segment,
+            offset: self.segment_len,
+            len,
+        };
+        self.index.write_all(&encode_entry(&entry))?;
+        self.segment_len += u64::from(len);
+
+        Ok(entry)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.check_failed()?;
+
+        let res = self.writer.flush().and_then(|_| self.index.flush());
+        self.failed = res.is_err();
+        res
+    }
+
+    fn check_failed(&self) -> io::Result<()> {
+        if self.failed {
+            return Err(io::Error::other(
+                "An earlier write failed, the recorder must be recreated",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Records every part of the stream. Resolves to the recorder, flushed, when
+    /// the stream ends.
+    pub fn record<S>(self, parts: S) -> impl Future<Item = Self, Error = Error>
+    where
+        S: Stream<Item = Part, Error = Error>,
+    {
+        parts
+            .fold(self, |mut recorder, part| {
+                blocking(move || recorder.write(&part).map(|_| recorder))
+            })
+            .and_then(|mut recorder| blocking(move || recorder.flush().map(|_| recorder)))
+    }
+}
+
+/// Runs `f` with `tokio_threadpool::blocking`, or directly when not on a
+/// threadpool, e.g. on a current thread runtime.
+fn blocking<T, F>(f: F) -> impl Future<Item = T, Error = Error>
+where
+    F: FnOnce() -> io::Result<T>,
+{
+    let mut f = Some(f);
+
+    future::poll_fn(move || {
+        let mut run = || f.take().expect("Polled after completion")();
+
+        let res = match tokio_threadpool::blocking(&mut run) {
+            Ok(Async::Ready(res)) => res,
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Err(_) => run(),
+        };
+
+        res.map(Async::Ready).map_err(Error::from)
+    })
+}
+
+/// Read access to a recording directory.
+pub struct Recording {
+    dir: PathBuf,
+    index: Vec<IndexEntry>,
+}
+
+impl Recording {
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        let index = read_index(&dir)?;
+
+        Ok(Self { dir, index })
+    }
+
+    /// All recorded parts, in the order they were written.
+    pub fn entries(&self) -> &[IndexEntry] {
+        &self.index
+    }
+
+    /// The parts that arrived within `from..to`.
+    pub fn range(&self, from: SystemTime, to: SystemTime) -> &[IndexEntry] {
+        let start = self.index.partition_point(|e| e.arrived < from);
+        let end = self.index.partition_point(|e| e.arrived < to);
+
+        &self.index[start..end.max(start)]
+    }
+
+    pub fn read(&self, entry: &IndexEntry) -> io::Result<RecordedPart> {
+        let mut file = File::open(segment_path(&self.dir, entry.segment))?;
+        read_record(&mut file, entry)
+    }
+
+    /// Reads the given entries, keeping the current segment file open between
+    /// consecutive entries of the same segment.
+    pub fn parts<'a>(
+        &'a self,
+        entries: &'a [IndexEntry],
+    ) -> impl Iterator<Item = io::Result<RecordedPart>> + 'a {
+        let mut open = None;
+        entries
+            .iter()
+            .map(move |entry| read_entry(&self.dir, &mut open, entry))
+    }
+
+    /// Consumes the recording, returning an iterator over all its parts.
+    pub fn into_parts(self) -> RecordedParts {
+        RecordedParts {
+            dir: self.dir,
+            entries: self.index.into_iter(),
+            open: None,
+        }
+    }
+}
+
+/// Owning iterator over the parts of a recording, see `Recording::into_parts`.
+pub struct RecordedParts {
+    dir: PathBuf,
+    entries: std::vec::IntoIter<IndexEntry>,
+    open: Option<(u32, File)>,
+}
+
+impl Iterator for RecordedParts {
+    type Item = io::Result<RecordedPart>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.entries.next()?;
+        Some(read_entry(&self.dir, &mut self.open, &entry))
+    }
+}
+
+fn read_entry(
+    dir: &Path,
+    open: &mut Option<(u32, File)>,
+    entry: &IndexEntry,
+) -> io::Result<RecordedPart> {
+    let file = match *open {
+        Some((segment, ref mut file)) if segment == entry.segment => file,
+        _ => {
+            let file = File::open(segment_path(dir, entry.segment))?;
+            &mut open.insert((entry.segment, file)).1
+        }
+    };
+
+    read_record(file, entry)
+}
+
+fn read_record(file: &mut File, entry: &IndexEntry) -> io::Result<RecordedPart> {
+    file.seek(SeekFrom::Start(entry.offset))?;
+
+    let mut data = vec![0u8; entry.len as usize];
+    file.read_exact(&mut data)?;
+
+    let (arrived, part) = decode_record(Bytes::from(data))?;
+    Ok(RecordedPart { arrived, part })
+}
+
+/// Decodes a single record, which must be exactly one record long.
+fn decode_record(mut data: Bytes) -> io::Result<(SystemTime, Part)> {
+    if data.len() < RECORD_HEADER_LEN {
+        return Err(invalid("Record shorter than its header"));
+    }
+
+    let arrived = u64::from_be_bytes(array(&data[0..8]));
+    let headers_len = u32::from_be_bytes(array(&data[8..12])) as usize;
+    let body_len = u32::from_be_bytes(array(&data[12..16])) as usize;
+
+    if data.len() != RECORD_HEADER_LEN + headers_len + body_len {
+        return Err(invalid("Record length does not match the index"));
+    }
+
+    data.advance(RECORD_HEADER_LEN);
+    let headers = data.split_to(headers_len);
+
+    Ok((from_micros(arrived), Part::from_raw(headers, data)))
+}
+
+fn segment_path(dir: &Path, segment: u32) -> PathBuf {
+    dir.join(format!("{:08}.seg", segment))
+}
+
+fn create_segment(dir: &Path, segment: u32) -> io::Result<BufWriter<File>> {
+    let mut writer = BufWriter::new(File::create(segment_path(dir, segment))?);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[VERSION])?;
+
+    Ok(writer)
+}
+
+fn read_index(dir: &Path) -> io::Result<Vec<IndexEntry>> {
+    let data = match fs::read(dir.join(INDEX_FILE)) {
+        Ok(data) => data,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    // A trailing partial entry is left by an interrupted write, skip it.
+    let entries = data.chunks_exact(INDEX_ENTRY_LEN).map(|e| IndexEntry {
+        arrived: from_micros(u64::from_be_bytes(array(&e[0..8]))),
+        segment: u32::from_be_bytes(array(&e[8..12])),
+        offset: u64::from_be_bytes(array(&e[12..20])),
+        len: u32::from_be_bytes(array(&e[20..24])),
+    });
+
+    // After a crash the index may point at records that never reached their
+    // segment, keep the entries before the first of those.
+    let mut segment_len = None;
+    let mut res = Vec::new();
+    for entry in entries {
+        let len = match segment_len {
+            Some((segment, len)) if segment == entry.segment => len,
+            _ => {
+                let len = match fs::metadata(segment_path(dir, entry.segment)) {
+                    Ok(meta) => meta.len(),
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => 0,
+                    Err(e) => return Err(e),
+                };
+                segment_len = Some((entry.segment, len));
+                len
+            }
+        };
+
+        if entry.offset + u64::from(entry.len) > len {
+            log::warn!("Ignoring index entries from incomplete record {:?}", entry);
+            break;
+        }
+        res.push(entry);
+    }
+
+    Ok(res)
+}
+
+fn encode_entry(entry: &IndexEntry) -> [u8; INDEX_ENTRY_LEN] {
+    let mut bs = [0u8; INDEX_ENTRY_LEN];
+    bs[0..8].copy_from_slice(&micros(entry.arrived).to_be_bytes());
+    bs[8..12].copy_from_slice(&entry.segment.to_be_bytes());
+    bs[12..20].copy_from_slice(&entry.offset.to_be_bytes());
+    bs[20..24].copy_from_slice(&entry.len.to_be_bytes());
+    bs
+}
+
+fn array<const N: usize>(bs: &[u8]) -> [u8; N] {
+    let mut arr = [0u8; N];
+    arr.copy_from_slice(bs);
+    arr
+}
+
+fn micros(t: SystemTime) -> u64 {
+    let d = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+    d.as_secs() * 1_000_000 + u64::from(d.subsec_micros())
+}
+
+fn from_micros(us: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_micros(us)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("hyper_multipart-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn part(i: u64) -> Part {
+        Part::from(
+            format!(
+                "Content-Type: image/jpeg\r\nX-Index: {}\r\n\r\nframe{}",
+                i, i
+            )
+            .as_bytes(),
+        )
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn record_and_query_by_time() {
+        let dir = temp_dir("record");
+
+        let mut recorder = Recorder::create(&dir).unwrap().with_segment_size(100);
+        for i in 0..10 {
+            recorder.write_at(&part(i), at(1000 + i)).unwrap();
+        }
+        recorder.flush().unwrap();
+
+        let recording = Recording::open(&dir).unwrap();
+        assert_eq!(10, recording.entries().len());
+        assert!(recording.entries().last().unwrap().segment > 0);
+
+        let range = recording.range(at(1003), at(1006));
+        assert_eq!(3, range.len());
+
+        let parts: Vec<RecordedPart> = recording.parts(range).map(|p| p.unwrap()).collect();
+        assert_eq!(at(1003), parts[0].arrived);
+        assert_eq!(b"frame3", parts[0].part.body());
+        assert_eq!(
+            &b"Content-Type: image/jpeg\r\nX-Index: 5"[..],
+            &parts[2].part.header_data()[..]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn append_to_existing_recording() {
+        let dir = temp_dir("append");
+
+        let mut recorder = Recorder::create(&dir).unwrap();
+        recorder.write_at(&part(0), at(10)).unwrap();
+        drop(recorder);
+
+        let mut recorder = Recorder::create(&dir).unwrap();
+        // Arrival times before the last recorded one are clamped.
+        recorder.write_at(&part(1), at(5)).unwrap();
+        recorder.flush().unwrap();
+
+        let recording = Recording::open(&dir).unwrap();
+        let entries = recording.entries();
+        assert_eq!(2, entries.len());
+        assert_eq!(1, entries[1].segment);
+        assert_eq!(at(10), entries[1].arrived);
+
+        let second = recording.read(&entries[1]).unwrap();
+        assert_eq!(b"frame1", second.part.body());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn ignore_index_entries_past_segment_end() {
+        let dir = temp_dir("crash");
+
+        let mut recorder = Recorder::create(&dir).unwrap();
+        let first = recorder.write_at(&part(0), at(10)).unwrap();
+        recorder.flush().unwrap();
+
+        // The index entry of a second record was written, its bytes were not.
+        let lost = IndexEntry {
+            offset: first.offset + u64::from(first.len),
+            ..first
+        };
+        recorder.index.write_all(&encode_entry(&lost)).unwrap();
+        drop(recorder);
+
+        assert_eq!(vec![first], Recording::open(&dir).unwrap().entries());
+
+        let mut recorder = Recorder::create(&dir).unwrap();
+        recorder.write_at(&part(1), at(20)).unwrap();
+        recorder.flush().unwrap();
+
+        let recording = Recording::open(&dir).unwrap();
+        let bodies: Vec<Bytes> = recording
+            .into_parts()
+            .map(|p| p.unwrap().part.into_body())
+            .collect();
+        assert_eq!(vec![&b"frame0"[..], &b"frame1"[..]], bodies);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    #[test]
+    fn record_stream_on_any_runtime() {
+        let dir = temp_dir("stream");
+        let parts = || futures::stream::iter_ok::<_, Error>((0..3).map(part).collect::<Vec<_>>());
+
+        // The threadpool runs the writes as blocking sections.
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let recorder = Recorder::create(&dir).unwrap();
+        rt.block_on(recorder.record(parts())).unwrap();
+
+        // A current thread runtime has no threadpool, the writes block it.
+        let mut rt = tokio::runtime::current_thread::Runtime::new().unwrap();
+        let recorder = Recorder::create(&dir).unwrap();
+        rt.block_on(recorder.record(parts())).unwrap();
+
+        assert_eq!(6, Recording::open(&dir).unwrap().entries().len());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn refuse_writes_after_a_failed_write() {
+        let dir = temp_dir("full");
+        fs::create_dir_all(&dir).unwrap();
+        // The second segment cannot be written to.
+        std::os::unix::fs::symlink("/dev/full", segment_path(&dir, 1)).unwrap();
+
+        let mut recorder = Recorder::create(&dir).unwrap().with_segment_size(1);
+        recorder.write_at(&part(0), at(10)).unwrap();
+
+        let large = Part::from(&vec![b'x'; 64 * 1024][..]);
+        assert!(recorder.write_at(&large, at(20)).is_err());
+        // The segment may end in a partial record, nothing is appended to it.
+        assert!(recorder.write_at(&part(1), at(30)).is_err());
+        assert!(recorder.flush().is_err());
+        drop(recorder);
+
+        fs::remove_file(segment_path(&dir, 1)).unwrap();
+        let mut recorder = Recorder::create(&dir).unwrap();
+        recorder.write_at(&part(2), at(40)).unwrap();
+        recorder.flush().unwrap();
+
+        let recording = Recording::open(&dir).unwrap();
+        let bodies: Vec<Bytes> = recording
+            .into_parts()
+            .map(|p| p.unwrap().part.into_body())
+            .collect();
+        assert_eq!(vec![&b"frame0"[..], &b"frame2"[..]], bodies);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,321 @@
+use crate::{
+    boundary,
+    parser::{BoundaryParser, ParseResult},
+    record::{RecordedPart, Recording},
+    Error, Part,
+};
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{Async, Future, Poll, Stream};
+use std::{
+    fs, io,
+    path::Path,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::timer::Delay;
+
+/// How fast a `Replay` emits its parts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pace {
+    /// Keep the original time between parts.
+    Original,
+    /// Original timing sped up by the given factor, 2.0 plays twice as fast.
+    Speed(f64),
+    /// Emit the parts as fast as they are polled.
+    Unthrottled,
+}
+
+type Source = Box<dyn Iterator<Item = io::Result<(Option<SystemTime>, Part)>> + Send>;
+
+/// Replays a saved multipart capture as a stream of parts.
+///
+/// Captures are either raw multipart bodies, as dumped from a response, or a
+/// directory written by `Recorder`. Recordings keep the arrival time of every
+/// part. Raw dumps have no arrival times, so their timing is taken from the
+/// `X-Timestamp` header of the parts, when present.
+///
+/// Unless the pace is `Pace::Unthrottled` the stream must be polled within a
+/// tokio runtime, since the timing uses its timer.
+pub struct Replay {
+    source: Source,
+    /// The boundary of a dump, or a generated one for a recording.
+    boundary: String,
+    pace: Pace,
+    start: Option<(Instant, SystemTime)>,
+    pending: Option<(Delay, Part)>,
+}
+
+impl Replay {
+    /// Opens a capture, a directory is read as a recording and a file as a raw
+    /// multipart dump.
+    pub fn open<P: AsRef<Path>>(path: P, pace: Pace) -> Result<Self, Error> {
+        let path = path.as_ref();
+
+        if path.is_dir() {
+            Ok(Self::from_recording(Recording::open(path)?, pace))
+        } else {
+            Self::from_dump(Bytes::from(fs::read(path)?), None, pace)
+        }
+    }
+
+    pub fn from_recording(recording: Recording, pace: Pace) -> Self {
+        let parts = recording
+            .into_parts()
+            .map(|res| res.map(|RecordedPart { arrived, part }| (Some(arrived), part)));
+
+        Self::new(Box::new(parts), boundary::generate(), pace)
+    }
+
+    /// Replays a raw multipart body. If no boundary is given it is taken from the
+    /// first line of the dump starting with `--`. A trailing incomplete part is
+    /// ignored.
+    pub fn from_dump(dump: Bytes, boundary: Option<&str>, pace: Pace) -> Result<Self, Error> {
+        let boundary = match boundary {
+            Some(b) => b.to_string(),
+            None => detect_boundary(&dump)
+                .ok_or_else(|| Error::malformed("No boundary found in multipart dump"))?,
+        };
+
+        let mut parser = BoundaryParser::with_capacity(&boundary, 0);
+        parser.add_bytes(dump);
+        parser.end_of_input();
+
+        let mut parts = Vec::new();
+        loop {
+            match parser.parse() {
+                ParseResult::Ready(bs) => {
+                    let part = Part::from(bs);
+                    let captured = crate::frame_stats::capture_time(&part)
+                        .filter(|secs| *secs >= 0.0)
+                        .map(|secs| UNIX_EPOCH + Duration::from_micros((secs * 1e6) as u64));
+
+                    parts.push(Ok((captured, part)));
+                }
+                ParseResult::Done | ParseResult::NotReady => break,
+                ParseResult::Err(e) => return Err(e),
+            }
+        }
+
+        Ok(Self::new(Box::new(parts.into_iter()), boundary, pace))
+    }
+
+    fn new(source: Source, boundary: String, pace: Pace) -> Self {
+        Self {
+            source,
+            boundary,
+            pace,
+            start: None,
+            pending: None,
+        }
+    }
+
+    /// The boundary `into_body` delimits the parts with. For a dump this is the
+    /// boundary of the dump, for a recording a generated one.
+    pub fn boundary(&self) -> &str {
+        &self.boundary
+    }
+
+    /// Re-encodes the replayed parts as a multipart body, delimited by
+    /// `boundary()`. The parts of a dump are emitted as they were in the dump.
+    /// The matching content type is `multipart/x-mixed-replace; boundary=...`.
+    ///
+    /// The body fails with `Error::MalformedMultipart` if a part contains the
+    /// boundary, as the parts cannot be checked before they are replayed.
+    pub fn into_body(self) -> hyper::Body {
+        let boundary = self.boundary.clone();
+        let delimiter = format!("--{}", boundary);
+        let closing = Bytes::from(format!("{}--\r\n", delimiter));
+
+        let parts = self.and_then(move |part| {
+            if boundary::collides(&boundary, part.header_data())
+                || boundary::collides(&boundary, part.body())
+            {
+                return Err(Error::malformed("Replayed part contains the boundary"));
+            }
+
+            Ok(encode_part(&delimiter, &part))
+        });
+        let end = futures::stream::once(Ok(closing));
+
+        hyper::Body::wrap_stream(parts.chain(end))
+    }
+
+    /// When a part that arrived at `arrived` is due.
+    fn due(&mut self, arrived: Option<SystemTime>) -> Option<Instant> {
+        let speed = match self.pace {
+            Pace::Original => 1.0,
+            Pace::Speed(s) if s > 0.0 => s,
+            _ => return None,
+        };
+
+        let arrived = arrived?;
+        let (started, first) = *self.start.get_or_insert((Instant::now(), arrived));
+        let offset = arrived.duration_since(first).unwrap_or_default();
+
+        Some(started + offset.div_f64(speed))
+    }
+}
+
+impl Stream for Replay {
+    type Item = Part;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Part>, Error> {
+        if let Some((ref mut delay, _)) = self.pending {
+            if let Async::NotReady = delay.poll().map_err(Error::inner)? {
+                return Ok(Async::NotReady);
+            }
+
+            return Ok(Async::Ready(self.pending.take().map(|(_, part)| part)));
+        }
+
+        let (arrived, part) = match self.source.next() {
+            None => return Ok(Async::Ready(None)),
+            Some(res) => res?,
+        };
+
+        match self.due(arrived) {
+            Some(due) if due > Instant::now() => {
+                self.pending = Some((Delay::new(due), part));
+                self.poll()
+            }
+            _ => Ok(Async::Ready(Some(part))),
+        }
+    }
+}
+
+fn detect_boundary(dump: &[u8]) -> Option<String> {
+    dump.split(|b| *b == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+        .find(|line| line.starts_with(b"--") && line.len() > 2)
+        .and_then(|line| std::str::from_utf8(&line[2..]).ok())
+        .map(|b| b.trim_end().to_string())
+}
+
+fn encode_part(delimiter: &str, part: &Part) -> Bytes {
+    let headers = part.header_data();
+    let mut buf = BytesMut::with_capacity(delimiter.len() + headers.len() + part.body_len() + 8);
+
+    buf.put_slice(delimiter.as_bytes());
+    buf.put_slice(b"\r\n");
+    if !headers.is_empty() {
+        buf.put_slice(headers);
+        buf.put_slice(b"\r\n");
+    }
+    buf.put_slice(b"\r\n");
+    buf.put_slice(part.body());
+    buf.put_slice(b"\r\n");
+
+    buf.freeze()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{Multipart, Recorder};
+
+    const DUMP: &str = "--frame\r
+Content-Type: image/jpeg\r
+X-Timestamp: 100.0\r
+\r
+frame1\r
+--frame\r
+Content-Type: image/jpeg\r
+X-Timestamp: 100.2\r
+\r
+frame2\r
+--frame\r
+Content-Type: image/jpeg\r
+X-Timestamp: 100.4\r
+\r
+frame3\r
+--frame\r
+Content-Type: image/jp";
+
+    fn bodies(s: impl Stream<Item = Part, Error = Error>) -> Vec<String> {
+        s.wait()
+            .map(|p| String::from_utf8(p.unwrap().body().to_vec()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn replay_dump_unthrottled() {
+        let replay = Replay::from_dump(Bytes::from(DUMP), None, Pace::Unthrottled).unwrap();
+        assert_eq!(vec!["frame1", "frame2", "frame3"], bodies(replay));
+    }
+
+    #[test]
+    fn replay_dump_with_speed() {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let replay = Replay::from_dump(Bytes::from(DUMP), None, Pace::Speed(2.0)).unwrap();
+
+        let started = Instant::now();
+        let parts = rt.block_on(replay.collect()).unwrap();
+        let elapsed = started.elapsed();
+
+        assert_eq!(3, parts.len());
+        assert!(elapsed >= Duration::from_millis(190), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(400), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn replay_dump_as_body() {
+        let replay = Replay::from_dump(Bytes::from(DUMP), None, Pace::Unthrottled).unwrap();
+        assert_eq!("frame", replay.boundary());
+
+        let raw = replay.into_body().concat2().wait().unwrap();
+        let complete = &DUMP[..DUMP.rfind("--frame").unwrap()];
+        assert_eq!(format!("{}--frame--\r\n", complete).as_bytes(), &raw[..]);
+    }
+
+    #[test]
+    fn replay_body_rejects_colliding_part() {
+        let replay = Replay::new(
+            Box::new(Some(Ok((None, Part::from(&b"\r\nsee --b"[..])))).into_iter()),
+            "b".to_string(),
+            Pace::Unthrottled,
+        );
+
+        match replay.into_body().concat2().wait() {
+            Err(e) => assert!(e.to_string().contains("boundary"), "{}", e),
+            Ok(body) => panic!("Expected an error, got {:?}", body),
+        }
+    }
+
+    #[test]
+    fn replay_recording_as_body() {
+        let dir =
+            std::env::temp_dir().join(format!("hyper_multipart-replay-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut recorder = Recorder::create(&dir).unwrap();
+        let start = SystemTime::now();
+        for i in 0..3 {
+            let part = Part::from(format!("Content-Type: text/plain\r\n\r\npart{}", i).as_bytes());
+            recorder
+                .write_at(&part, start + Duration::from_millis(10 * i))
+                .unwrap();
+        }
+        recorder.flush().unwrap();
+
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let replay = Replay::open(&dir, Pace::Original).unwrap();
+        let content_type = format!(
+            "multipart/x-mixed-replace; boundary=\"{}\"",
+            replay.boundary()
+        );
+        let raw = rt.block_on(replay.into_body().concat2()).unwrap();
+
+        let response = http::Response::builder()
+            .header("Content-Type", content_type)
+            .body(raw.into_bytes())
+            .unwrap();
+
+        assert_eq!(
+            vec!["part0", "part1", "part2"],
+            bodies(response.into_multipart().unwrap())
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
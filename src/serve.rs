@@ -0,0 +1,282 @@
+use crate::{
+    boundary,
+    broadcast::{Broadcast, BroadcastHandle, Lagging},
+    Error, Part,
+};
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{stream, Stream};
+use http::header::{HeaderValue, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE, PRAGMA};
+
+/// The upstream of the broadcast, parts encoded for sending: the headers, a
+/// Content-Length among them, the body and the CRLF ending the part.
+pub type Encoded<S> = stream::Map<S, fn(Part) -> Bytes>;
+
+/// Serves a stream of parts to any number of http clients as
+/// `multipart/x-mixed-replace`, the format browsers display as a live MJPEG
+/// stream.
+///
+/// Every client gets its own queue of parts. A client that cannot keep up is
+/// handled according to its `Lagging` policy, by default it skips to the newest
+/// part. When a client disconnects its queue is dropped.
+///
+/// Parts are encoded once, before they are handed out to the clients, so every
+/// client shares the same bytes. A part containing the boundary is skipped,
+/// it would end early on the client side. When the upstream ends, the
+/// responses are closed with the closing delimiter.
+///
+/// ```no_run
+/// # use futures::{Future, Stream};
+/// # use hyper_multipart::{Error, MixedReplace, Part};
+/// # fn serve(parts: impl Stream<Item = Part, Error = Error> + Send + 'static) {
+/// let (mjpeg, broadcast) = MixedReplace::new(parts);
+///
+/// let server = hyper::Server::bind(&([127, 0, 0, 1], 8080).into())
+///     .serve(move || {
+///         let mjpeg = mjpeg.clone();
+///         hyper::service::service_fn_ok(move |_req| mjpeg.response())
+///     })
+///     .map_err(|e| eprintln!("Server error: {}", e));
+///
+/// tokio::run(futures::lazy(|| {
+///     tokio::spawn(broadcast);
+///     server
+/// }));
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct MixedReplace {
+    handle: BroadcastHandle<Bytes>,
+    boundary: String,
+    capacity: usize,
+    lagging: Lagging,
+}
+
+impl MixedReplace {
+    /// Returns the server side handle together with the future driving the
+    /// upstream, which must be spawned.
+    pub fn new<S>(parts: S) -> (Self, Broadcast<Encoded<S>>)
+    where
+        S: Stream<Item = Part, Error = Error>,
+    {
+        let (broadcast, handle) = Broadcast::new(parts.map(encode as fn(Part) -> Bytes));
+
+        let server = Self {
+            handle,
+            boundary: boundary::generate(),
+            capacity: 1,
+            lagging: Lagging::DropOldest,
+        };
+
+        (server, broadcast)
+    }
+
+    /// Queue up to `capacity` parts per client, handling clients falling behind
+    /// according to `lagging`.
+    pub fn with_queue(mut self, capacity: usize, lagging: Lagging) -> Self {
+        self.capacity = capacity;
+        self.lagging = lagging;
+        self
+    }
+
+    /// Uses the given boundary instead of a generated one. Fails with
+    /// `Error::InvalidBoundary` unless it is valid according to RFC 2046.
+    pub fn with_boundary<S: Into<String>>(mut self, boundary: S) -> Result<Self, Error> {
+        let boundary = boundary.into();
+        if !boundary::is_valid(&boundary) {
+            return Err(Error::InvalidBoundary(boundary));
+        }
+
+        self.boundary = boundary;
+        Ok(self)
+    }
+
+    /// Number of connected clients.
+    pub fn clients(&self) -> usize {
+        self.handle.subscribers()
+    }
+
+    /// A response streaming the parts to a new client, starting from the next
+    /// part of the upstream.
+    pub fn response(&self) -> hyper::Response<hyper::Body> {
+        let subscriber = self.handle.subscribe(self.capacity, self.lagging);
+        let delimiter = Bytes::from(format!("--{}\r\n", self.boundary));
+        let closing = Bytes::from(format!("--{}--\r\n", self.boundary));
+        let boundary = self.boundary.clone();
+
+        let body = subscriber
+            .filter(move |encoded| {
+                let collides = boundary::collides(&boundary, encoded);
+                if collides {
+                    log::warn!("Skipping a part containing the boundary {:?}", boundary);
+                }
+                !collides
+            })
+            .map(move |encoded| stream::iter_ok::<_, Error>(vec![delimiter.clone(), encoded]))
+            .flatten()
+            .chain(stream::once(Ok(closing)));
+        let content_type = format!("multipart/x-mixed-replace; boundary=\"{}\"", self.boundary);
+
+        hyper::Response::builder()
+            .header(CONTENT_TYPE, content_type.as_str())
+            .header(CACHE_CONTROL, "no-cache, no-store, must-revalidate")
+            .header(PRAGMA, "no-cache")
+            .body(hyper::Body::wrap_stream(body))
+            .expect("Building multipart response")
+    }
+}
+
+/// Encodes the part as it follows its delimiter line, adding a Content-Length
+/// header when missing so clients know when the part is complete without
+/// waiting for the next delimiter.
+fn encode(part: Part) -> Bytes {
+    let mut headers = part.header_data().to_vec();
+
+    if !part.headers().contains_key(CONTENT_LENGTH) {
+        if !headers.is_empty() {
+            headers.extend_from_slice(b"\r\n");
+        }
+        headers.extend_from_slice(b"Content-Length: ");
+        headers.extend_from_slice(HeaderValue::from(part.body_len()).as_bytes());
+    }
+
+    let mut buf = BytesMut::with_capacity(headers.len() + part.body_len() + 6);
+    buf.put_slice(&headers);
+    buf.put_slice(b"\r\n\r\n");
+    buf.put_slice(part.body());
+    buf.put_slice(b"\r\n");
+
+    buf.freeze()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::Multipart;
+    use futures::{sync::mpsc, Future};
+    use hyper::{service::service_fn_ok, Server};
+    use std::{
+        thread,
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn serve_parts_to_many_clients() {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+
+        let (tx, rx) = mpsc::unbounded::<Part>();
+        let (mjpeg, broadcast) =
+            MixedReplace::new(rx.map_err(|_| Error::malformed("Upstream closed")));
+        rt.spawn(broadcast);
+
+        let served = mjpeg.clone();
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(move || {
+            let mjpeg = served.clone();
+            service_fn_ok(move |_req| mjpeg.response())
+        });
+        let uri: http::Uri = format!("http://{}/", server.local_addr()).parse().unwrap();
+        rt.spawn(server.map_err(|e| panic!("Server failed: {}", e)));
+
+        // Keep producing frames until the test is done.
+        let producer = thread::spawn(move || {
+            for i in 0.. {
+                let part =
+                    Part::from(format!("Content-Type: image/jpeg\r\n\r\nframe{}", i).as_bytes());
+                if tx.unbounded_send(part).is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+        });
+
+        let client = hyper::Client::new();
+        let take_two = |uri: http::Uri| {
+            client.get(uri).map_err(Error::from).and_then(|response| {
+                let content_type = response.headers()["content-type"].to_str().unwrap();
+                assert!(content_type.starts_with("multipart/x-mixed-replace"));
+
+                response.into_multipart().unwrap().take(2).collect()
+            })
+        };
+
+        let both = take_two(uri.clone()).join(take_two(uri));
+        let (a, b) = rt.block_on(both).unwrap();
+
+        for part in a.iter().chain(b.iter()) {
+            assert!(part.body().starts_with(b"frame"));
+            assert_eq!(
+                part.body_len().to_string(),
+                part.headers()["content-length"]
+            );
+        }
+
+        // The clients are gone, so are their queues.
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while mjpeg.clients() > 0 {
+            assert!(Instant::now() < deadline, "Clients were not cleaned up");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        drop(mjpeg);
+        drop(rt);
+        producer.join().unwrap();
+    }
+
+    #[test]
+    fn quoted_and_validated_boundary() {
+        let (mjpeg, _broadcast) = MixedReplace::new(futures::stream::empty());
+
+        assert!(matches!(
+            mjpeg.clone().with_boundary("a\nb"),
+            Err(Error::InvalidBoundary(_))
+        ));
+        assert!(mjpeg.clone().with_boundary("a\"b").is_err());
+
+        let response = mjpeg.with_boundary("simple boundary").unwrap().response();
+        assert_eq!(
+            "multipart/x-mixed-replace; boundary=\"simple boundary\"",
+            response.headers()[CONTENT_TYPE]
+        );
+    }
+
+    #[test]
+    fn encode_adds_content_length() {
+        let encoded = encode(Part::from(&b"Content-Type: image/jpeg\r\n\r\njpeg"[..]));
+        assert_eq!(
+            &b"Content-Type: image/jpeg\r\nContent-Length: 4\r\n\r\njpeg\r\n"[..],
+            &encoded[..]
+        );
+    }
+
+    #[test]
+    fn skip_colliding_parts_and_close_the_body() {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+
+        let (tx, rx) = mpsc::unbounded::<Part>();
+        let (mjpeg, broadcast) =
+            MixedReplace::new(rx.map_err(|_| Error::malformed("Upstream closed")));
+        let mjpeg = mjpeg
+            .with_queue(3, Lagging::DropNewest)
+            .with_boundary("frame")
+            .unwrap();
+
+        let response = mjpeg.response();
+        for body in &["one", "--frame in the body", "three"] {
+            tx.unbounded_send(Part::from(format!("\r\n{}", body).as_bytes()))
+                .unwrap();
+        }
+        drop(tx);
+        rt.block_on(broadcast).unwrap();
+
+        let raw = rt
+            .block_on(response.into_body().concat2())
+            .unwrap()
+            .into_bytes();
+        assert_eq!(
+            &b"--frame\r\nContent-Length: 3\r\n\r\none\r\n\
+               --frame\r\nContent-Length: 5\r\n\r\nthree\r\n\
+               --frame--\r\n"[..],
+            &raw[..]
+        );
+    }
+}
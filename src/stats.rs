@@ -0,0 +1,70 @@
+use std::sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    Arc,
+};
+
+/// Cloneable handle to the progress counters of a `MultipartChunks` stream.
+/// All clones share the same counters, so a handle can be moved to another
+/// task and read while the stream is being polled.
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    inner: Arc<Counters>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    bytes_received: AtomicU64,
+    parts: AtomicU64,
+    buffered: AtomicUsize,
+    current_part_bytes: AtomicUsize,
+    length_mismatches: AtomicU64,
+}
+
+impl Stats {
+    /// Total number of body bytes received from the inner stream.
+    pub fn bytes_received(&self) -> u64 {
+        self.inner.bytes_received.load(Ordering::Relaxed)
+    }
+
+    /// Number of parts produced so far.
+    pub fn parts(&self) -> u64 {
+        self.inner.parts.load(Ordering::Relaxed)
+    }
+
+    /// Number of bytes currently held in the parser buffer.
+    pub fn buffered(&self) -> usize {
+        self.inner.buffered.load(Ordering::Relaxed)
+    }
+
+    /// Bytes received so far for the part in progress.
+    pub fn current_part_bytes(&self) -> usize {
+        self.inner.current_part_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Number of parts whose body did not match their `Content-Length`, when
+    /// checked with `LengthCheck::Warn`.
+    pub fn length_mismatches(&self) -> u64 {
+        self.inner.length_mismatches.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn add_received(&self, n: usize) {
+        self.inner
+            .bytes_received
+            .fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_part(&self) {
+        self.inner.parts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_length_mismatch(&self) {
+        self.inner.length_mismatches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_buffer(&self, buffered: usize, current_part_bytes: usize) {
+        self.inner.buffered.store(buffered, Ordering::Relaxed);
+        self.inner
+            .current_part_bytes
+            .store(current_part_bytes, Ordering::Relaxed);
+    }
+}
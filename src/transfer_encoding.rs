@@ -0,0 +1,315 @@
+//! Decoding of `Content-Transfer-Encoding`, RFC 2045.
+//!
+//! `Part::decoded_body` decodes a whole part. For the streaming event API a
+//! `Decoder` can be created from the headers of `Event::PartStart` and fed the
+//! following `Event::BodyChunk`s.
+
+use crate::Error;
+use bytes::Bytes;
+use http::header::{HeaderMap, HeaderValue};
+
+/// The transfer encodings of RFC 2045.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransferEncoding {
+    SevenBit,
+    EightBit,
+    Binary,
+    Base64,
+    QuotedPrintable,
+}
+
+impl TransferEncoding {
+    /// Parses a `Content-Transfer-Encoding` value, case-insensitively.
+    pub fn parse(value: &str) -> Result<Self, Error> {
+        let encoding = match value.trim().to_ascii_lowercase().as_str() {
+            "7bit" => TransferEncoding::SevenBit,
+            "8bit" => TransferEncoding::EightBit,
+            "binary" => TransferEncoding::Binary,
+            "base64" => TransferEncoding::Base64,
+            "quoted-printable" => TransferEncoding::QuotedPrintable,
+            _ => return Err(Error::UnknownTransferEncoding(value.to_string())),
+        };
+
+        Ok(encoding)
+    }
+
+    /// Reads the `Content-Transfer-Encoding` header, defaulting to 7bit.
+    pub fn from_headers(headers: &HeaderMap<HeaderValue>) -> Result<Self, Error> {
+        match headers.get("content-transfer-encoding") {
+            None => Ok(TransferEncoding::SevenBit),
+            Some(value) => match value.to_str() {
+                Ok(value) => TransferEncoding::parse(value),
+                Err(_) => Err(Error::UnknownTransferEncoding(
+                    String::from_utf8_lossy(value.as_bytes()).into_owned(),
+                )),
+            },
+        }
+    }
+
+    /// 7bit, 8bit and binary bodies are left as they are.
+    pub fn is_identity(self) -> bool {
+        match self {
+            TransferEncoding::SevenBit | TransferEncoding::EightBit | TransferEncoding::Binary => {
+                true
+            }
+            TransferEncoding::Base64 | TransferEncoding::QuotedPrintable => false,
+        }
+    }
+
+    /// Decodes an entire body.
+    pub fn decode(self, data: &Bytes) -> Result<Bytes, Error> {
+        if self.is_identity() {
+            return Ok(data.clone());
+        }
+
+        let mut decoder = self.decoder();
+        let mut res = decoder.decode_chunk(data)?.to_vec();
+        res.extend_from_slice(&decoder.finish()?);
+        Ok(Bytes::from(res))
+    }
+
+    pub fn decoder(self) -> Decoder {
+        Decoder {
+            encoding: self,
+            pending: Vec::new(),
+            padded: false,
+        }
+    }
+}
+
+/// Incremental decoder, for bodies arriving in chunks.
+#[derive(Debug)]
+pub struct Decoder {
+    encoding: TransferEncoding,
+    /// Input that cannot be decoded until more bytes have arrived.
+    pending: Vec<u8>,
+    /// Base64 padding has been seen, only whitespace may follow.
+    padded: bool,
+}
+
+impl Decoder {
+    /// Decodes as much of `chunk` as possible, keeping incomplete input for
+    /// the next call.
+    pub fn decode_chunk(&mut self, chunk: &[u8]) -> Result<Bytes, Error> {
+        match self.encoding {
+            TransferEncoding::Base64 => self.base64(chunk),
+            TransferEncoding::QuotedPrintable => {
+                self.pending.extend_from_slice(chunk);
+
+                // Decode line by line, the last line may still be incomplete.
+                match self.pending.iter().rposition(|b| *b == b'\n') {
+                    Some(i) => {
+                        let rest = self.pending.split_off(i + 1);
+                        let lines = std::mem::replace(&mut self.pending, rest);
+                        Ok(Bytes::from(quoted_printable(&lines)))
+                    }
+                    None => Ok(Bytes::new()),
+                }
+            }
+            _ => Ok(Bytes::from(chunk.to_vec())),
+        }
+    }
+
+    /// Decodes what is left once the body is complete.
+    pub fn finish(self) -> Result<Bytes, Error> {
+        match self.encoding {
+            TransferEncoding::Base64 if !self.pending.is_empty() => Err(Error::Decode(format!(
+                "base64 body ends with {} dangling characters",
+                self.pending.len()
+            ))),
+            TransferEncoding::QuotedPrintable => Ok(Bytes::from(quoted_printable(&self.pending))),
+            _ => Ok(Bytes::new()),
+        }
+    }
+
+    fn base64(&mut self, chunk: &[u8]) -> Result<Bytes, Error> {
+        let mut res = Vec::with_capacity(chunk.len() / 4 * 3 + 3);
+
+        for &b in chunk {
+            if b.is_ascii_whitespace() {
+                continue;
+            }
+
+            if self.padded {
+                return Err(Error::Decode("base64 data after padding".to_string()));
+            }
+
+            if base64_value(b).is_none() && b != b'=' {
+                return Err(Error::Decode(format!("invalid base64 byte {:#04x}", b)));
+            }
+
+            self.pending.push(b);
+            if self.pending.len() < 4 {
+                continue;
+            }
+
+            let quad = std::mem::take(&mut self.pending);
+            let padding = quad.iter().rev().take_while(|b| **b == b'=').count();
+            if padding > 2 || quad[..4 - padding].contains(&b'=') {
+                return Err(Error::Decode("misplaced base64 padding".to_string()));
+            }
+
+            let n = quad[..4 - padding]
+                .iter()
+                .enumerate()
+                .fold(0u32, |n, (i, b)| {
+                    n | (u32::from(base64_value(*b).unwrap_or(0)) << (18 - 6 * i))
+                });
+            res.extend_from_slice(&n.to_be_bytes()[1..4 - padding]);
+            self.padded = padding > 0;
+        }
+
+        Ok(Bytes::from(res))
+    }
+}
+
+fn base64_value(b: u8) -> Option<u8> {
+    match b {
+        b'A'..=b'Z' => Some(b - b'A'),
+        b'a'..=b'z' => Some(b - b'a' + 26),
+        b'0'..=b'9' => Some(b - b'0' + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    }
+}
+
+/// Decodes complete quoted-printable lines. Soft line breaks are removed,
+/// trailing whitespace is dropped and malformed escapes are kept as they are.
+fn quoted_printable(data: &[u8]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(data.len());
+
+    for line in data.split_inclusive(|b| *b == b'\n') {
+        let content = line.strip_suffix(b"\n").unwrap_or(line);
+        let content = content.strip_suffix(b"\r").unwrap_or(content);
+        let ending = &line[content.len()..];
+
+        let end = content
+            .iter()
+            .rposition(|b| *b != b' ' && *b != b'\t')
+            .map_or(0, |i| i + 1);
+        let content = &content[..end];
+
+        let (content, soft_break) = match content.strip_suffix(b"=") {
+            Some(content) => (content, true),
+            None => (content, false),
+        };
+
+        let mut i = 0;
+        while i < content.len() {
+            let hex = content
+                .get(i + 1..i + 3)
+                .filter(|h| h.iter().all(u8::is_ascii_hexdigit))
+                .and_then(|h| std::str::from_utf8(h).ok())
+                .and_then(|h| u8::from_str_radix(h, 16).ok());
+
+            match (content[i], hex) {
+                (b'=', Some(b)) => {
+                    res.push(b);
+                    i += 3;
+                }
+                (b, _) => {
+                    res.push(b);
+                    i += 1;
+                }
+            }
+        }
+
+        if !soft_break {
+            res.extend_from_slice(ending);
+        }
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn decode(encoding: TransferEncoding, data: &[u8]) -> Result<Vec<u8>, Error> {
+        encoding
+            .decode(&Bytes::from(data.to_vec()))
+            .map(|bs| bs.to_vec())
+    }
+
+    #[test]
+    fn base64() {
+        let enc = TransferEncoding::parse("Base64").unwrap();
+
+        assert_eq!(
+            b"hello world".to_vec(),
+            decode(enc, b"aGVsbG8g\r\nd29ybGQ=\r\n").unwrap()
+        );
+        assert_eq!(b"ab".to_vec(), decode(enc, b"YWI=").unwrap());
+        assert_eq!(b"a".to_vec(), decode(enc, b"YQ==").unwrap());
+
+        assert!(decode(enc, b"YWJ").is_err());
+        assert!(decode(enc, b"Y*I=").is_err());
+        assert!(decode(enc, b"YQ==YQ==").is_err());
+    }
+
+    #[test]
+    fn quoted_printable() {
+        let enc = TransferEncoding::QuotedPrintable;
+
+        assert_eq!(
+            "r\u{e4}ksm\u{f6}rg\u{e5}s a=b long line\r\nnext  \r\n".as_bytes(),
+            &decode(
+                enc,
+                b"r=C3=A4ksm=c3=b6rg=C3=A5s a=3Db long =\r\nline  \r\nnext=20=20\r\n"
+            )
+            .unwrap()[..]
+        );
+
+        // Malformed escapes are kept.
+        assert_eq!(b"100=ZZ".to_vec(), decode(enc, b"100=ZZ").unwrap());
+    }
+
+    #[test]
+    fn decode_in_chunks() {
+        for (enc, data, exp) in &[
+            (
+                TransferEncoding::Base64,
+                &b"aGVsbG8g\r\nd29ybGQ=\r\n"[..],
+                &b"hello world"[..],
+            ),
+            (
+                TransferEncoding::QuotedPrintable,
+                &b"a=3Db soft=\r\nbreak\r\nend=21"[..],
+                &b"a=b softbreak\r\nend!"[..],
+            ),
+        ] {
+            let mut decoder = enc.decoder();
+            let mut res = Vec::new();
+            for chunk in data.chunks(3) {
+                res.extend_from_slice(&decoder.decode_chunk(chunk).unwrap());
+            }
+            res.extend_from_slice(&decoder.finish().unwrap());
+
+            assert_eq!(exp, &&res[..]);
+        }
+    }
+
+    #[test]
+    fn identity_and_unknown_encodings() {
+        let body = Bytes::from(&b"\x00\xffraw"[..]);
+        for name in &["7bit", "8BIT", " binary "] {
+            let enc = TransferEncoding::parse(name).unwrap();
+            assert_eq!(body, enc.decode(&body).unwrap());
+        }
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(
+            TransferEncoding::SevenBit,
+            TransferEncoding::from_headers(&headers).unwrap()
+        );
+
+        headers.insert("content-transfer-encoding", "x-uuencode".parse().unwrap());
+        match TransferEncoding::from_headers(&headers) {
+            Err(Error::UnknownTransferEncoding(name)) => assert_eq!("x-uuencode", name),
+            other => panic!("Expected UnknownTransferEncoding, got {:?}", other),
+        }
+    }
+}